@@ -7,7 +7,9 @@ psql -v ON_ERROR_STOP=1 -U "$POSTGRES_USER" <<-EOSQL
 CREATE EXTENSION IF NOT EXISTS timescaledb;
 
 CREATE TABLE logs (
+    id BIGSERIAL,
     time TIMESTAMP,
+    ingest_time TIMESTAMP,
     level TEXT,
     source TEXT,
     words TEXT[],
@@ -30,10 +32,19 @@ CREATE TABLE cols (
   name TEXT PRIMARY KEY
 );
 
+CREATE TABLE dead_letters (
+    id BIGSERIAL PRIMARY KEY,
+    time TIMESTAMP,
+    source TEXT,
+    reason TEXT,
+    payload BYTEA
+);
+
 CREATE INDEX idx_logdata ON logs USING GIN (logdata);
 CREATE INDEX idx_words ON logs USING GIN (words);
+CREATE INDEX idx_time_id ON logs (time, id);
 
-INSERT INTO filters (name, query) VALUES ('logs', 'true');
+INSERT INTO filters (name, query) VALUES ('logs', '*');
 INSERT INTO column_filter (column_name, filter_name) VALUES ('Data', 'logs');
 INSERT INTO cols (name, query) VALUES ('Data', 'logdata');
 
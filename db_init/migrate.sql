@@ -0,0 +1,61 @@
+-- Brings a database created by an older init-access-node.sh to the current schema.
+-- Every statement can run again, so this is safe to apply after each upgrade:
+--   psql -v ON_ERROR_STOP=1 -U postgres -f db_init/migrate.sql
+
+-- time the consumer received each log, the time column now being the event time
+ALTER TABLE logs ADD COLUMN IF NOT EXISTS ingest_time TIMESTAMP;
+
+-- ids ordering rows within a timestamp, for paging and tailing. A column with a default taken
+-- from a sequence cannot be added to a compressed hypertable, nor filled in one statement
+-- without rewriting it whole, so the column is added empty and the rows written before get
+-- their ids one chunk at a time, compressed chunks being decompressed then compressed again.
+CREATE SEQUENCE IF NOT EXISTS logs_id_seq;
+ALTER TABLE logs ADD COLUMN IF NOT EXISTS id BIGINT;
+ALTER TABLE logs ALTER COLUMN id SET DEFAULT nextval('logs_id_seq');
+ALTER SEQUENCE logs_id_seq OWNED BY logs.id;
+DO $$
+DECLARE
+    chunk regclass;
+    compressed boolean;
+    missing boolean;
+BEGIN
+    IF to_regclass('timescaledb_information.chunks') IS NULL THEN
+        UPDATE logs SET id = nextval('logs_id_seq') WHERE id IS NULL;
+        RETURN;
+    END IF;
+    FOR chunk, compressed IN
+        SELECT format('%I.%I', chunk_schema, chunk_name)::regclass, is_compressed
+        FROM timescaledb_information.chunks
+        WHERE hypertable_schema = 'public' AND hypertable_name = 'logs'
+        ORDER BY range_start
+    LOOP
+        EXECUTE format('SELECT EXISTS (SELECT 1 FROM %s WHERE id IS NULL)', chunk) INTO missing;
+        IF compressed AND NOT missing THEN
+            -- rows of compressed chunks are only seen through the hypertable
+            SELECT EXISTS (SELECT 1 FROM logs WHERE tableoid = chunk AND id IS NULL) INTO missing;
+        END IF;
+        CONTINUE WHEN NOT missing;
+        IF compressed THEN
+            PERFORM decompress_chunk(chunk);
+        END IF;
+        EXECUTE format('UPDATE %s SET id = nextval(''logs_id_seq'') WHERE id IS NULL', chunk);
+        IF compressed THEN
+            PERFORM compress_chunk(chunk);
+        END IF;
+        -- each chunk is kept once done, should the migration stop
+        COMMIT;
+    END LOOP;
+END $$;
+CREATE INDEX IF NOT EXISTS idx_time_id ON logs (time, id);
+
+-- messages and rows the consumer could not store as logs
+CREATE TABLE IF NOT EXISTS dead_letters (
+    id BIGSERIAL PRIMARY KEY,
+    time TIMESTAMP,
+    source TEXT,
+    reason TEXT,
+    payload BYTEA
+);
+
+-- filters are written in the query language rather than SQL, where everything is matched by `*`
+UPDATE filters SET query = '*' WHERE lower(trim(query)) = 'true';
@@ -0,0 +1,1207 @@
+//! Settings of the ingest binaries, read from a TOML file then overridden by
+//! `LOGDOG_*` environment variables and command line flags.
+
+use std::{collections::BTreeMap, fmt, net::SocketAddr, path::Path, str::FromStr, time::Duration};
+
+use amqprs::connection::OpenConnectionArguments;
+use chrono::format::{Item, StrftimeItems};
+use clap::Parser;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{formats::Format, timestamp};
+
+#[derive(Debug)]
+pub struct ConfigError(pub(crate) String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Read `path`, or `default_path` if it exists, or fall back on the defaults.
+fn read_file<T: DeserializeOwned + Default>(
+    path: Option<&Path>,
+    default_path: &str,
+) -> Result<T, ConfigError> {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(default_path).exists() => Path::new(default_path),
+        None => return Ok(T::default()),
+    };
+    let text = std::fs::read_to_string(path)
+        .map_err(|error| ConfigError(format!("cannot read {}: {}", path.display(), error)))?;
+    toml::from_str(&text).map_err(|error| ConfigError(format!("{}: {}", path.display(), error)))
+}
+
+/// Broker, and the queue logs go through.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AmqpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub vhost: String,
+    pub exchange: String,
+    pub queue: String,
+    pub routing_key: String,
+}
+
+impl Default for AmqpConfig {
+    fn default() -> Self {
+        AmqpConfig {
+            host: "localhost".to_owned(),
+            port: 5672,
+            username: "guest".to_owned(),
+            password: "guest".to_owned(),
+            vhost: "/".to_owned(),
+            exchange: "amq.topic".to_owned(),
+            queue: "amqprs.examples.basic".to_owned(),
+            routing_key: "amqprs.example".to_owned(),
+        }
+    }
+}
+
+impl AmqpConfig {
+    pub fn connection_args(&self) -> OpenConnectionArguments {
+        let mut args =
+            OpenConnectionArguments::new(&self.host, self.port, &self.username, &self.password);
+        args.virtual_host(&self.vhost);
+        args
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (name, value) in [
+            ("amqp.host", &self.host),
+            ("amqp.exchange", &self.exchange),
+            ("amqp.queue", &self.queue),
+            ("amqp.routing_key", &self.routing_key),
+        ] {
+            if value.is_empty() {
+                return Err(ConfigError(format!("{} must not be empty", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flags overriding `[amqp]`, shared by the binaries.
+#[derive(Debug, clap::Args)]
+struct AmqpArgs {
+    #[arg(long, env = "LOGDOG_AMQP_HOST")]
+    amqp_host: Option<String>,
+    #[arg(long, env = "LOGDOG_AMQP_PORT")]
+    amqp_port: Option<u16>,
+    #[arg(long, env = "LOGDOG_AMQP_USERNAME")]
+    amqp_username: Option<String>,
+    #[arg(long, env = "LOGDOG_AMQP_PASSWORD", hide_env_values = true)]
+    amqp_password: Option<String>,
+    #[arg(long, env = "LOGDOG_AMQP_VHOST")]
+    amqp_vhost: Option<String>,
+    #[arg(long, env = "LOGDOG_AMQP_EXCHANGE")]
+    amqp_exchange: Option<String>,
+    #[arg(long, env = "LOGDOG_AMQP_QUEUE")]
+    amqp_queue: Option<String>,
+    #[arg(long, env = "LOGDOG_AMQP_ROUTING_KEY")]
+    amqp_routing_key: Option<String>,
+}
+
+impl AmqpArgs {
+    fn apply(self, amqp: &mut AmqpConfig) {
+        for (value, target) in [
+            (self.amqp_host, &mut amqp.host),
+            (self.amqp_username, &mut amqp.username),
+            (self.amqp_password, &mut amqp.password),
+            (self.amqp_vhost, &mut amqp.vhost),
+            (self.amqp_exchange, &mut amqp.exchange),
+            (self.amqp_queue, &mut amqp.queue),
+            (self.amqp_routing_key, &mut amqp.routing_key),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+        if let Some(port) = self.amqp_port {
+            amqp.port = port;
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Copy logs from the AMQP queue into the logs table")]
+struct ConsumerArgs {
+    /// TOML configuration file.
+    #[arg(long, env = "LOGDOG_CONFIG")]
+    config: Option<std::path::PathBuf>,
+    #[command(flatten)]
+    amqp: AmqpArgs,
+    /// Postgres connection string, URL or key=value pairs.
+    #[arg(long, env = "LOGDOG_DATABASE_DSN", hide_env_values = true)]
+    database_dsn: Option<String>,
+    /// Threads of the async runtime.
+    #[arg(long, env = "LOGDOG_WORKER_THREADS")]
+    worker_threads: Option<usize>,
+    /// AMQP connections consuming the queue.
+    #[arg(long, env = "LOGDOG_CONNECTIONS")]
+    connections: Option<usize>,
+    /// Database connections writing batches.
+    #[arg(long, env = "LOGDOG_WRITERS")]
+    writers: Option<usize>,
+    /// Most rows written in one COPY.
+    #[arg(long, env = "LOGDOG_BATCH_SIZE")]
+    batch_size: Option<usize>,
+    /// Most message bytes written in one COPY.
+    #[arg(long, env = "LOGDOG_BATCH_MAX_BYTES")]
+    batch_max_bytes: Option<usize>,
+    /// Milliseconds a batch waits for more messages before being written.
+    #[arg(long, env = "LOGDOG_BATCH_LINGER")]
+    batch_linger_ms: Option<u64>,
+    /// Messages waiting for each writer before consumers are held back.
+    #[arg(long, env = "LOGDOG_WRITER_QUEUE")]
+    writer_queue: Option<usize>,
+    /// Messages each AMQP connection may hold unacked.
+    #[arg(long, env = "LOGDOG_PREFETCH")]
+    prefetch: Option<u16>,
+    /// Milliseconds before the first database retry, doubled on each failure.
+    #[arg(long, env = "LOGDOG_RETRY_INITIAL_DELAY")]
+    retry_initial_delay_ms: Option<u64>,
+    /// Longest wait between database retries, in milliseconds.
+    #[arg(long, env = "LOGDOG_RETRY_MAX_DELAY")]
+    retry_max_delay_ms: Option<u64>,
+    /// Seconds to write pending batches after SIGTERM or SIGINT.
+    #[arg(long, env = "LOGDOG_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Address serving Prometheus metrics on /metrics, empty to disable.
+    #[arg(long, env = "LOGDOG_METRICS_LISTEN")]
+    metrics_listen: Option<String>,
+    /// Format of the messages of each routing key, as comma separated `key=format` pairs.
+    #[arg(long, env = "LOGDOG_FORMATS", value_delimiter = ',', value_parser = parse_routing_format)]
+    formats: Option<Vec<(String, Format)>>,
+    /// TOML file of rules extracting fields from log messages.
+    #[arg(long, env = "LOGDOG_RULES_FILE")]
+    rules_file: Option<std::path::PathBuf>,
+    /// Fields holding the event time of a log, comma separated, looked up in order.
+    #[arg(long, env = "LOGDOG_TIME_FIELDS", value_delimiter = ',')]
+    time_fields: Option<Vec<String>>,
+    /// strftime formats of event times besides RFC 3339 and epochs, semicolon separated.
+    #[arg(long, env = "LOGDOG_TIME_FORMATS", value_delimiter = ';')]
+    time_formats: Option<Vec<String>>,
+}
+
+fn parse_routing_format(text: &str) -> Result<(String, Format), String> {
+    let (routing_key, format) = text
+        .split_once('=')
+        .ok_or_else(|| format!("expected routing_key=format, got {:?}", text))?;
+    Ok((routing_key.to_owned(), format.parse()?))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub dsn: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            dsn: "host=localhost user=postgres password=test".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsumerSettings {
+    pub worker_threads: usize,
+    /// AMQP connections, each feeding the writer `index % writers`.
+    pub connections: usize,
+    pub writers: usize,
+    /// A batch is written once it has `batch_size` rows, `batch_max_bytes` bytes of messages,
+    /// or `batch_linger_ms` after its first message, whichever comes first.
+    pub batch_size: usize,
+    pub batch_max_bytes: usize,
+    pub batch_linger_ms: u64,
+    pub writer_queue: usize,
+    /// Messages each connection holds unacked, waiting for their rows to be committed.
+    pub prefetch: u16,
+    /// Milliseconds before writers retry a failed connection or batch, doubled each time.
+    pub retry_initial_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    /// Seconds given to writers to commit what was received once asked to stop.
+    pub shutdown_timeout_secs: u64,
+    /// Address serving `/metrics`, empty to disable.
+    pub metrics_listen: String,
+    /// Format of the messages sent with each routing key, JSON arrays for the others.
+    /// Messages of other formats hold one log per line.
+    pub formats: BTreeMap<String, Format>,
+    /// Rules extracting fields from strings of each log before it is stored, see `rules`.
+    pub rules_file: Option<std::path::PathBuf>,
+    /// Fields looked up, in order, for the event time of each log, stored as `time`.
+    pub time_fields: Vec<String>,
+    /// strftime formats accepted for event times, besides RFC 3339 and epoch numbers.
+    pub time_formats: Vec<String>,
+}
+
+impl Default for ConsumerSettings {
+    fn default() -> Self {
+        ConsumerSettings {
+            worker_threads: 24,
+            connections: 8,
+            writers: 4,
+            batch_size: 20000,
+            batch_max_bytes: 16 * 1024 * 1024,
+            batch_linger_ms: 1000,
+            writer_queue: 1024,
+            prefetch: 256,
+            retry_initial_delay_ms: 500,
+            retry_max_delay_ms: 30000,
+            shutdown_timeout_secs: 30,
+            metrics_listen: "0.0.0.0:9898".to_owned(),
+            formats: BTreeMap::new(),
+            rules_file: None,
+            time_fields: timestamp::DEFAULT_FIELDS.map(str::to_owned).to_vec(),
+            time_formats: Vec::new(),
+        }
+    }
+}
+
+impl ConsumerSettings {
+    pub fn batch_linger(&self) -> Duration {
+        Duration::from_millis(self.batch_linger_ms)
+    }
+
+    pub fn retry_initial_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_initial_delay_ms)
+    }
+
+    pub fn retry_max_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_max_delay_ms)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    /// Where to serve metrics, checked by `validate`.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_listen.parse().ok()
+    }
+}
+
+/// Settings of `logdog-consumer`, from `logdog-consumer.toml` by default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsumerConfig {
+    pub amqp: AmqpConfig,
+    pub database: DatabaseConfig,
+    pub consumer: ConsumerSettings,
+}
+
+impl ConsumerConfig {
+    /// Read the file, apply environment variables and flags, and validate the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = ConsumerArgs::parse();
+        let mut config: ConsumerConfig = read_file(args.config.as_deref(), "logdog-consumer.toml")?;
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, args: ConsumerArgs) {
+        args.amqp.apply(&mut self.amqp);
+        let consumer = &mut self.consumer;
+        if let Some(dsn) = args.database_dsn {
+            self.database.dsn = dsn;
+        }
+        if let Some(prefetch) = args.prefetch {
+            consumer.prefetch = prefetch;
+        }
+        if let Some(delay) = args.retry_initial_delay_ms {
+            consumer.retry_initial_delay_ms = delay;
+        }
+        if let Some(delay) = args.retry_max_delay_ms {
+            consumer.retry_max_delay_ms = delay;
+        }
+        if let Some(timeout) = args.shutdown_timeout_secs {
+            consumer.shutdown_timeout_secs = timeout;
+        }
+        if let Some(listen) = args.metrics_listen {
+            consumer.metrics_listen = listen;
+        }
+        if let Some(formats) = args.formats {
+            consumer.formats = formats.into_iter().collect();
+        }
+        if let Some(rules_file) = args.rules_file {
+            consumer.rules_file = Some(rules_file);
+        }
+        for (value, target) in [
+            (args.time_fields, &mut consumer.time_fields),
+            (args.time_formats, &mut consumer.time_formats),
+        ] {
+            if let Some(value) = value {
+                *target = value
+                    .iter()
+                    .map(|item| item.trim())
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+            }
+        }
+        if let Some(linger) = args.batch_linger_ms {
+            consumer.batch_linger_ms = linger;
+        }
+        for (value, target) in [
+            (args.worker_threads, &mut consumer.worker_threads),
+            (args.connections, &mut consumer.connections),
+            (args.writers, &mut consumer.writers),
+            (args.batch_size, &mut consumer.batch_size),
+            (args.batch_max_bytes, &mut consumer.batch_max_bytes),
+            (args.writer_queue, &mut consumer.writer_queue),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.amqp.validate()?;
+        self.database
+            .dsn
+            .parse::<tokio_postgres::Config>()
+            .map_err(|error| ConfigError(format!("database.dsn: {}", error)))?;
+        let consumer = &self.consumer;
+        for (name, value) in [
+            ("consumer.worker_threads", consumer.worker_threads),
+            ("consumer.writers", consumer.writers),
+            ("consumer.batch_size", consumer.batch_size),
+            ("consumer.batch_max_bytes", consumer.batch_max_bytes),
+            ("consumer.writer_queue", consumer.writer_queue),
+            ("consumer.prefetch", consumer.prefetch as usize),
+        ] {
+            if value == 0 {
+                return Err(ConfigError(format!("{} must be at least 1", name)));
+            }
+        }
+        if consumer.retry_initial_delay_ms == 0
+            || consumer.retry_initial_delay_ms > consumer.retry_max_delay_ms
+        {
+            return Err(ConfigError(
+                "consumer.retry_initial_delay_ms must be between 1 and consumer.retry_max_delay_ms"
+                    .to_owned(),
+            ));
+        }
+        if !consumer.metrics_listen.is_empty() && consumer.metrics_address().is_none() {
+            return Err(ConfigError(format!(
+                "consumer.metrics_listen {:?} is not a socket address",
+                consumer.metrics_listen
+            )));
+        }
+        for format in &consumer.time_formats {
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(ConfigError(format!(
+                    "consumer.time_formats {:?} is not a strftime format",
+                    format
+                )));
+            }
+        }
+        // every writer needs at least one connection, and as many as the others
+        if consumer.connections < consumer.writers
+            || !consumer.connections.is_multiple_of(consumer.writers)
+        {
+            return Err(ConfigError(format!(
+                "consumer.connections ({}) must be a multiple of consumer.writers ({})",
+                consumer.connections, consumer.writers
+            )));
+        }
+        Ok(())
+    }
+
+    /// Writer receiving the rows of each AMQP connection.
+    pub fn writer_of(&self, connection: usize) -> usize {
+        connection % self.consumer.writers
+    }
+
+    /// Format of the messages sent with `routing_key`.
+    pub fn format_of(&self, routing_key: &str) -> Format {
+        self.consumer
+            .formats
+            .get(routing_key)
+            .copied()
+            .unwrap_or(Format::Json)
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Publish JSON log lines read from stdin or tailed files to the AMQP exchange")]
+struct ProducerArgs {
+    /// TOML configuration file.
+    #[arg(long, env = "LOGDOG_CONFIG")]
+    config: Option<std::path::PathBuf>,
+    #[command(flatten)]
+    amqp: AmqpArgs,
+    /// Name tagging published logs, the host name by default.
+    #[arg(long, env = "LOGDOG_SOURCE")]
+    source: Option<String>,
+    /// Most lines published in one message.
+    #[arg(long, env = "LOGDOG_BATCH_LINES")]
+    batch_lines: Option<usize>,
+    /// Seconds to publish pending lines after SIGTERM or SIGINT.
+    #[arg(long, env = "LOGDOG_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Paths or globs of files to tail instead of reading stdin, comma separated.
+    #[arg(long, env = "LOGDOG_FILES", value_delimiter = ',')]
+    files: Option<Vec<String>>,
+    /// File keeping how far each tailed file was published.
+    #[arg(long, env = "LOGDOG_CHECKPOINT_FILE")]
+    checkpoint_file: Option<std::path::PathBuf>,
+    /// Milliseconds between checks of tailed files for new lines.
+    #[arg(long, env = "LOGDOG_POLL_INTERVAL_MS")]
+    poll_interval_ms: Option<u64>,
+    /// Directory keeping batches that could not be published yet.
+    #[arg(long, env = "LOGDOG_SPOOL_DIR")]
+    spool_dir: Option<std::path::PathBuf>,
+    /// Most bytes kept in the spool before the oldest batches are dropped.
+    #[arg(long, env = "LOGDOG_SPOOL_MAX_BYTES")]
+    spool_max_bytes: Option<u64>,
+    /// Milliseconds before the first AMQP reconnection, doubled on each failure.
+    #[arg(long, env = "LOGDOG_RETRY_INITIAL_DELAY")]
+    retry_initial_delay_ms: Option<u64>,
+    /// Longest wait between AMQP reconnections, in milliseconds.
+    #[arg(long, env = "LOGDOG_RETRY_MAX_DELAY")]
+    retry_max_delay_ms: Option<u64>,
+    /// Address serving Prometheus metrics on /metrics, empty to disable.
+    #[arg(long, env = "LOGDOG_METRICS_LISTEN")]
+    metrics_listen: Option<String>,
+    /// Regex matching the first line of a multiline event.
+    #[arg(long, env = "LOGDOG_MULTILINE_START")]
+    multiline_start: Option<String>,
+    /// Regex matching the following lines of a multiline event.
+    #[arg(long, env = "LOGDOG_MULTILINE_CONTINUATION")]
+    multiline_continuation: Option<String>,
+    /// Most lines in one multiline event.
+    #[arg(long, env = "LOGDOG_MULTILINE_MAX_LINES")]
+    multiline_max_lines: Option<usize>,
+    /// Most bytes in one multiline event.
+    #[arg(long, env = "LOGDOG_MULTILINE_MAX_BYTES")]
+    multiline_max_bytes: Option<usize>,
+    /// Milliseconds without a new line before a multiline event is published.
+    #[arg(long, env = "LOGDOG_MULTILINE_TIMEOUT")]
+    multiline_timeout_ms: Option<u64>,
+    /// Format of the lines read: `json`, `logfmt`, `syslog` or `clf`.
+    #[arg(long, env = "LOGDOG_FORMAT")]
+    format: Option<Format>,
+    /// What to do with lines that do not parse: `wrap` or `drop`.
+    #[arg(long, env = "LOGDOG_NON_JSON")]
+    non_json: Option<NonJsonMode>,
+}
+
+/// Handling of the lines that are not JSON objects, or do not parse in the producer format.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NonJsonMode {
+    /// Publish them as `{"message": line, "level": guessed level}`.
+    Wrap,
+    /// Leave them out, with a warning.
+    Drop,
+}
+
+impl FromStr for NonJsonMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(NonJsonMode::Wrap),
+            "drop" => Ok(NonJsonMode::Drop),
+            _ => Err(format!(
+                "unknown non-JSON mode {:?}, expected wrap or drop",
+                s
+            )),
+        }
+    }
+}
+
+/// How lines are assembled into events, disabled unless `start` or `continuation` is set.
+///
+/// A line is appended to the event before it when it does not match `start` and, if set,
+/// matches `continuation`; any other line begins a new event.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultilineConfig {
+    pub start: Option<String>,
+    pub continuation: Option<String>,
+    pub max_lines: usize,
+    pub max_bytes: usize,
+    /// Milliseconds an event waits for more lines before being published.
+    pub timeout_ms: u64,
+}
+
+impl Default for MultilineConfig {
+    fn default() -> Self {
+        MultilineConfig {
+            start: None,
+            continuation: None,
+            max_lines: 500,
+            max_bytes: 1024 * 1024,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+impl MultilineConfig {
+    pub fn enabled(&self) -> bool {
+        self.start.is_some() || self.continuation.is_some()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (name, pattern) in [
+            ("producer.multiline.start", &self.start),
+            ("producer.multiline.continuation", &self.continuation),
+        ] {
+            if let Some(pattern) = pattern {
+                regex::Regex::new(pattern)
+                    .map_err(|error| ConfigError(format!("{}: {}", name, error)))?;
+            }
+        }
+        for (name, value) in [
+            ("producer.multiline.max_lines", self.max_lines),
+            ("producer.multiline.max_bytes", self.max_bytes),
+        ] {
+            if value == 0 {
+                return Err(ConfigError(format!("{} must be at least 1", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProducerSettings {
+    pub source: Option<String>,
+    pub batch_lines: usize,
+    /// Seconds given to publish what was read once asked to stop.
+    pub shutdown_timeout_secs: u64,
+    /// Paths or globs tailed instead of stdin when not empty.
+    pub files: Vec<String>,
+    pub checkpoint_file: std::path::PathBuf,
+    pub poll_interval_ms: u64,
+    /// Batches are written here while the broker cannot be reached, and published again
+    /// in order once it can; past `spool_max_bytes` the oldest ones are dropped.
+    pub spool_dir: std::path::PathBuf,
+    pub spool_max_bytes: u64,
+    /// Milliseconds before reconnecting to the broker, doubled each time.
+    pub retry_initial_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    /// Address serving `/metrics`, empty to disable.
+    pub metrics_listen: String,
+    /// Lines are parsed in this format and published as JSON objects.
+    pub format: Format,
+    pub non_json: NonJsonMode,
+    pub multiline: MultilineConfig,
+}
+
+impl Default for ProducerSettings {
+    fn default() -> Self {
+        ProducerSettings {
+            source: None,
+            batch_lines: 32,
+            shutdown_timeout_secs: 10,
+            files: Vec::new(),
+            checkpoint_file: "logdog-producer.checkpoint".into(),
+            poll_interval_ms: 250,
+            spool_dir: "logdog-producer.spool".into(),
+            spool_max_bytes: 256 * 1024 * 1024,
+            retry_initial_delay_ms: 500,
+            retry_max_delay_ms: 30000,
+            metrics_listen: String::new(),
+            format: Format::Json,
+            non_json: NonJsonMode::Wrap,
+            multiline: MultilineConfig::default(),
+        }
+    }
+}
+
+impl ProducerSettings {
+    /// The configured source, or the host name.
+    pub fn source(&self) -> String {
+        self.source
+            .clone()
+            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned())
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    pub fn retry_initial_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_initial_delay_ms)
+    }
+
+    pub fn retry_max_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_max_delay_ms)
+    }
+
+    /// Where to serve metrics, checked by `validate`.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_listen.parse().ok()
+    }
+}
+
+/// Settings of `logdog-producer`, from `logdog-producer.toml` by default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProducerConfig {
+    pub amqp: AmqpConfig,
+    pub producer: ProducerSettings,
+}
+
+impl ProducerConfig {
+    /// Read the file, apply environment variables and flags, and validate the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = ProducerArgs::parse();
+        let mut config: ProducerConfig = read_file(args.config.as_deref(), "logdog-producer.toml")?;
+        args.amqp.apply(&mut config.amqp);
+        let producer = &mut config.producer;
+        if let Some(source) = args.source {
+            producer.source = Some(source);
+        }
+        if let Some(batch_lines) = args.batch_lines {
+            producer.batch_lines = batch_lines;
+        }
+        if let Some(timeout) = args.shutdown_timeout_secs {
+            producer.shutdown_timeout_secs = timeout;
+        }
+        if let Some(files) = args.files {
+            producer.files = files;
+        }
+        if let Some(checkpoint_file) = args.checkpoint_file {
+            producer.checkpoint_file = checkpoint_file;
+        }
+        if let Some(poll_interval_ms) = args.poll_interval_ms {
+            producer.poll_interval_ms = poll_interval_ms;
+        }
+        if let Some(spool_dir) = args.spool_dir {
+            producer.spool_dir = spool_dir;
+        }
+        if let Some(spool_max_bytes) = args.spool_max_bytes {
+            producer.spool_max_bytes = spool_max_bytes;
+        }
+        if let Some(delay) = args.retry_initial_delay_ms {
+            producer.retry_initial_delay_ms = delay;
+        }
+        if let Some(delay) = args.retry_max_delay_ms {
+            producer.retry_max_delay_ms = delay;
+        }
+        if let Some(listen) = args.metrics_listen {
+            producer.metrics_listen = listen;
+        }
+        if let Some(format) = args.format {
+            producer.format = format;
+        }
+        if let Some(non_json) = args.non_json {
+            producer.non_json = non_json;
+        }
+        let multiline = &mut producer.multiline;
+        if let Some(start) = args.multiline_start {
+            multiline.start = Some(start);
+        }
+        if let Some(continuation) = args.multiline_continuation {
+            multiline.continuation = Some(continuation);
+        }
+        if let Some(max_lines) = args.multiline_max_lines {
+            multiline.max_lines = max_lines;
+        }
+        if let Some(max_bytes) = args.multiline_max_bytes {
+            multiline.max_bytes = max_bytes;
+        }
+        if let Some(timeout) = args.multiline_timeout_ms {
+            multiline.timeout_ms = timeout;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.amqp.validate()?;
+        if self.producer.batch_lines == 0 {
+            return Err(ConfigError(
+                "producer.batch_lines must be at least 1".to_owned(),
+            ));
+        }
+        for pattern in &self.producer.files {
+            glob::Pattern::new(pattern)
+                .map_err(|error| ConfigError(format!("producer.files {:?}: {}", pattern, error)))?;
+        }
+        if self.producer.poll_interval_ms == 0 {
+            return Err(ConfigError(
+                "producer.poll_interval_ms must be at least 1".to_owned(),
+            ));
+        }
+        let producer = &self.producer;
+        if producer.spool_max_bytes == 0 {
+            return Err(ConfigError(
+                "producer.spool_max_bytes must be at least 1".to_owned(),
+            ));
+        }
+        if producer.retry_initial_delay_ms == 0
+            || producer.retry_initial_delay_ms > producer.retry_max_delay_ms
+        {
+            return Err(ConfigError(
+                "producer.retry_initial_delay_ms must be between 1 and producer.retry_max_delay_ms"
+                    .to_owned(),
+            ));
+        }
+        if !producer.metrics_listen.is_empty() && producer.metrics_address().is_none() {
+            return Err(ConfigError(format!(
+                "producer.metrics_listen {:?} is not a socket address",
+                producer.metrics_listen
+            )));
+        }
+        producer.multiline.validate()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Scrape Prometheus endpoints and publish their samples as logs")]
+struct PromArgs {
+    /// TOML configuration file.
+    #[arg(long, env = "LOGDOG_CONFIG")]
+    config: Option<std::path::PathBuf>,
+    #[command(flatten)]
+    amqp: AmqpArgs,
+    /// URLs of Prometheus text endpoints, comma separated.
+    #[arg(long, env = "LOGDOG_PROM_TARGETS", value_delimiter = ',')]
+    targets: Option<Vec<String>>,
+    /// Seconds between scrapes.
+    #[arg(long, env = "LOGDOG_PROM_INTERVAL")]
+    interval_secs: Option<u64>,
+    /// Seconds a scrape may take.
+    #[arg(long, env = "LOGDOG_PROM_TIMEOUT")]
+    timeout_secs: Option<u64>,
+    /// Name tagging published samples, the host name by default.
+    #[arg(long, env = "LOGDOG_SOURCE")]
+    source: Option<String>,
+    /// Most samples published in one message.
+    #[arg(long, env = "LOGDOG_PROM_BATCH_SAMPLES")]
+    batch_samples: Option<usize>,
+    /// Directory keeping batches that could not be published yet.
+    #[arg(long, env = "LOGDOG_SPOOL_DIR")]
+    spool_dir: Option<std::path::PathBuf>,
+    /// Most bytes kept in the spool before the oldest batches are dropped.
+    #[arg(long, env = "LOGDOG_SPOOL_MAX_BYTES")]
+    spool_max_bytes: Option<u64>,
+    /// Milliseconds before the first AMQP reconnection, doubled on each failure.
+    #[arg(long, env = "LOGDOG_RETRY_INITIAL_DELAY")]
+    retry_initial_delay_ms: Option<u64>,
+    /// Longest wait between AMQP reconnections, in milliseconds.
+    #[arg(long, env = "LOGDOG_RETRY_MAX_DELAY")]
+    retry_max_delay_ms: Option<u64>,
+    /// Seconds to publish spooled samples after SIGTERM or SIGINT.
+    #[arg(long, env = "LOGDOG_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromSettings {
+    pub targets: Vec<String>,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    pub source: Option<String>,
+    pub batch_samples: usize,
+    /// Batches are written here while the broker cannot be reached, as for the producer.
+    pub spool_dir: std::path::PathBuf,
+    pub spool_max_bytes: u64,
+    pub retry_initial_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    /// Seconds given to publish the spool once asked to stop.
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for PromSettings {
+    fn default() -> Self {
+        PromSettings {
+            targets: Vec::new(),
+            interval_secs: 15,
+            timeout_secs: 10,
+            source: None,
+            batch_samples: 500,
+            spool_dir: "prom-producer.spool".into(),
+            spool_max_bytes: 256 * 1024 * 1024,
+            retry_initial_delay_ms: 500,
+            retry_max_delay_ms: 30000,
+            shutdown_timeout_secs: 10,
+        }
+    }
+}
+
+impl PromSettings {
+    /// The configured source, or the host name.
+    pub fn source(&self) -> String {
+        self.source
+            .clone()
+            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned())
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn retry_initial_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_initial_delay_ms)
+    }
+
+    pub fn retry_max_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_max_delay_ms)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+/// Settings of `prom-producer`, from `prom-producer.toml` by default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromConfig {
+    pub amqp: AmqpConfig,
+    pub prom: PromSettings,
+}
+
+impl PromConfig {
+    /// Read the file, apply environment variables and flags, and validate the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = PromArgs::parse();
+        let mut config: PromConfig = read_file(args.config.as_deref(), "prom-producer.toml")?;
+        args.amqp.apply(&mut config.amqp);
+        let prom = &mut config.prom;
+        if let Some(targets) = args.targets {
+            prom.targets = targets;
+        }
+        if let Some(source) = args.source {
+            prom.source = Some(source);
+        }
+        for (value, target) in [
+            (args.interval_secs, &mut prom.interval_secs),
+            (args.timeout_secs, &mut prom.timeout_secs),
+            (args.spool_max_bytes, &mut prom.spool_max_bytes),
+            (
+                args.retry_initial_delay_ms,
+                &mut prom.retry_initial_delay_ms,
+            ),
+            (args.retry_max_delay_ms, &mut prom.retry_max_delay_ms),
+            (args.shutdown_timeout_secs, &mut prom.shutdown_timeout_secs),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+        if let Some(batch_samples) = args.batch_samples {
+            prom.batch_samples = batch_samples;
+        }
+        if let Some(spool_dir) = args.spool_dir {
+            prom.spool_dir = spool_dir;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.amqp.validate()?;
+        let prom = &self.prom;
+        if prom.targets.is_empty() {
+            return Err(ConfigError(
+                "prom.targets must list at least one URL".to_owned(),
+            ));
+        }
+        for target in &prom.targets {
+            if !target.starts_with("http://") && !target.starts_with("https://") {
+                return Err(ConfigError(format!(
+                    "prom.targets {:?} is not an http(s) URL",
+                    target
+                )));
+            }
+        }
+        if prom.interval_secs == 0 || prom.timeout_secs == 0 || prom.batch_samples == 0 {
+            return Err(ConfigError(
+                "prom.interval_secs, prom.timeout_secs and prom.batch_samples must be at least 1"
+                    .to_owned(),
+            ));
+        }
+        if prom.spool_max_bytes == 0 {
+            return Err(ConfigError(
+                "prom.spool_max_bytes must be at least 1".to_owned(),
+            ));
+        }
+        if prom.retry_initial_delay_ms == 0 || prom.retry_initial_delay_ms > prom.retry_max_delay_ms
+        {
+            return Err(ConfigError(
+                "prom.retry_initial_delay_ms must be between 1 and prom.retry_max_delay_ms"
+                    .to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Receive syslog over UDP and TCP and publish it as logs")]
+struct SyslogArgs {
+    /// TOML configuration file.
+    #[arg(long, env = "LOGDOG_CONFIG")]
+    config: Option<std::path::PathBuf>,
+    #[command(flatten)]
+    amqp: AmqpArgs,
+    /// Address receiving syslog datagrams, empty to disable.
+    #[arg(long, env = "LOGDOG_SYSLOG_UDP_LISTEN")]
+    udp_listen: Option<String>,
+    /// Address accepting syslog over TCP, empty to disable.
+    #[arg(long, env = "LOGDOG_SYSLOG_TCP_LISTEN")]
+    tcp_listen: Option<String>,
+    /// Longest syslog message, in bytes.
+    #[arg(long, env = "LOGDOG_SYSLOG_MAX_MESSAGE_BYTES")]
+    max_message_bytes: Option<usize>,
+    /// Name tagging published logs, the host name by default.
+    #[arg(long, env = "LOGDOG_SOURCE")]
+    source: Option<String>,
+    /// Most messages published in one batch.
+    #[arg(long, env = "LOGDOG_BATCH_LINES")]
+    batch_lines: Option<usize>,
+    /// Milliseconds a batch waits for more messages before being published.
+    #[arg(long, env = "LOGDOG_BATCH_LINGER")]
+    batch_linger_ms: Option<u64>,
+    /// Directory keeping batches that could not be published yet.
+    #[arg(long, env = "LOGDOG_SPOOL_DIR")]
+    spool_dir: Option<std::path::PathBuf>,
+    /// Most bytes kept in the spool before the oldest batches are dropped.
+    #[arg(long, env = "LOGDOG_SPOOL_MAX_BYTES")]
+    spool_max_bytes: Option<u64>,
+    /// Milliseconds before the first AMQP reconnection, doubled on each failure.
+    #[arg(long, env = "LOGDOG_RETRY_INITIAL_DELAY")]
+    retry_initial_delay_ms: Option<u64>,
+    /// Longest wait between AMQP reconnections, in milliseconds.
+    #[arg(long, env = "LOGDOG_RETRY_MAX_DELAY")]
+    retry_max_delay_ms: Option<u64>,
+    /// Seconds to publish received messages after SIGTERM or SIGINT.
+    #[arg(long, env = "LOGDOG_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Address serving Prometheus metrics on /metrics, empty to disable.
+    #[arg(long, env = "LOGDOG_METRICS_LISTEN")]
+    metrics_listen: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogSettings {
+    /// Addresses receiving syslog, empty to disable either.
+    pub udp_listen: String,
+    pub tcp_listen: String,
+    /// Longer datagrams and TCP frames are truncated.
+    pub max_message_bytes: usize,
+    pub source: Option<String>,
+    /// A batch is published once it has `batch_lines` messages, or `batch_linger_ms`
+    /// after its first one.
+    pub batch_lines: usize,
+    pub batch_linger_ms: u64,
+    /// Batches are written here while the broker cannot be reached, as for the producer.
+    pub spool_dir: std::path::PathBuf,
+    pub spool_max_bytes: u64,
+    pub retry_initial_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    /// Seconds given to publish what was received once asked to stop.
+    pub shutdown_timeout_secs: u64,
+    /// Address serving `/metrics`, empty to disable.
+    pub metrics_listen: String,
+}
+
+impl Default for SyslogSettings {
+    fn default() -> Self {
+        SyslogSettings {
+            udp_listen: "0.0.0.0:5514".to_owned(),
+            tcp_listen: "0.0.0.0:5514".to_owned(),
+            max_message_bytes: 64 * 1024,
+            source: None,
+            batch_lines: 256,
+            batch_linger_ms: 1000,
+            spool_dir: "logdog-syslog.spool".into(),
+            spool_max_bytes: 256 * 1024 * 1024,
+            retry_initial_delay_ms: 500,
+            retry_max_delay_ms: 30000,
+            shutdown_timeout_secs: 10,
+            metrics_listen: String::new(),
+        }
+    }
+}
+
+impl SyslogSettings {
+    /// The configured source, or the host name.
+    pub fn source(&self) -> String {
+        self.source
+            .clone()
+            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned())
+    }
+
+    /// Where to receive datagrams, checked by `validate`.
+    pub fn udp_address(&self) -> Option<SocketAddr> {
+        self.udp_listen.parse().ok()
+    }
+
+    /// Where to accept connections, checked by `validate`.
+    pub fn tcp_address(&self) -> Option<SocketAddr> {
+        self.tcp_listen.parse().ok()
+    }
+
+    pub fn batch_linger(&self) -> Duration {
+        Duration::from_millis(self.batch_linger_ms)
+    }
+
+    pub fn retry_initial_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_initial_delay_ms)
+    }
+
+    pub fn retry_max_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_max_delay_ms)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    /// Where to serve metrics, checked by `validate`.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_listen.parse().ok()
+    }
+}
+
+/// Settings of `logdog-syslog`, from `logdog-syslog.toml` by default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    pub amqp: AmqpConfig,
+    pub syslog: SyslogSettings,
+}
+
+impl SyslogConfig {
+    /// Read the file, apply environment variables and flags, and validate the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = SyslogArgs::parse();
+        let mut config: SyslogConfig = read_file(args.config.as_deref(), "logdog-syslog.toml")?;
+        args.amqp.apply(&mut config.amqp);
+        let syslog = &mut config.syslog;
+        for (value, target) in [
+            (args.udp_listen, &mut syslog.udp_listen),
+            (args.tcp_listen, &mut syslog.tcp_listen),
+            (args.metrics_listen, &mut syslog.metrics_listen),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+        if let Some(source) = args.source {
+            syslog.source = Some(source);
+        }
+        for (value, target) in [
+            (args.max_message_bytes, &mut syslog.max_message_bytes),
+            (args.batch_lines, &mut syslog.batch_lines),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+        for (value, target) in [
+            (args.batch_linger_ms, &mut syslog.batch_linger_ms),
+            (args.spool_max_bytes, &mut syslog.spool_max_bytes),
+            (
+                args.retry_initial_delay_ms,
+                &mut syslog.retry_initial_delay_ms,
+            ),
+            (args.retry_max_delay_ms, &mut syslog.retry_max_delay_ms),
+            (
+                args.shutdown_timeout_secs,
+                &mut syslog.shutdown_timeout_secs,
+            ),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+        if let Some(spool_dir) = args.spool_dir {
+            syslog.spool_dir = spool_dir;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.amqp.validate()?;
+        let syslog = &self.syslog;
+        if syslog.udp_listen.is_empty() && syslog.tcp_listen.is_empty() {
+            return Err(ConfigError(
+                "syslog.udp_listen and syslog.tcp_listen cannot both be empty".to_owned(),
+            ));
+        }
+        for (name, listen, address) in [
+            (
+                "syslog.udp_listen",
+                &syslog.udp_listen,
+                syslog.udp_address(),
+            ),
+            (
+                "syslog.tcp_listen",
+                &syslog.tcp_listen,
+                syslog.tcp_address(),
+            ),
+            (
+                "syslog.metrics_listen",
+                &syslog.metrics_listen,
+                syslog.metrics_address(),
+            ),
+        ] {
+            if !listen.is_empty() && address.is_none() {
+                return Err(ConfigError(format!(
+                    "{} {:?} is not a socket address",
+                    name, listen
+                )));
+            }
+        }
+        for (name, value) in [
+            ("syslog.max_message_bytes", syslog.max_message_bytes),
+            ("syslog.batch_lines", syslog.batch_lines),
+        ] {
+            if value == 0 {
+                return Err(ConfigError(format!("{} must be at least 1", name)));
+            }
+        }
+        if syslog.spool_max_bytes == 0 {
+            return Err(ConfigError(
+                "syslog.spool_max_bytes must be at least 1".to_owned(),
+            ));
+        }
+        if syslog.retry_initial_delay_ms == 0
+            || syslog.retry_initial_delay_ms > syslog.retry_max_delay_ms
+        {
+            return Err(ConfigError(
+                "syslog.retry_initial_delay_ms must be between 1 and syslog.retry_max_delay_ms"
+                    .to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -1,135 +1,333 @@
-use std::{collections::HashSet, str::FromStr};
+use std::{cmp::min, collections::HashSet, fmt::Display, sync::Arc, time::Duration};
 
 use amqprs::{
-    channel::{BasicConsumeArguments, Channel, QueueBindArguments, QueueDeclareArguments},
+    channel::{
+        BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, BasicNackArguments,
+        BasicQosArguments, Channel, QueueBindArguments, QueueDeclareArguments,
+    },
     connection::Connection as amqpConnection,
-    connection::OpenConnectionArguments,
     consumer::BlockingConsumer,
     BasicProperties, Deliver,
 };
-use chrono;
-use futures::pin_mut;
+use futures::{future::join_all, pin_mut};
 use lazy_static::lazy_static;
+use logdog_rust::{
+    config::ConsumerConfig,
+    formats::Format,
+    metrics::{
+        self, AMQP_RECONNECTS, BATCH_BYTES, BATCH_FILL_SECONDS, BATCH_FLUSHES, BATCH_RETRIES,
+        BATCH_ROWS, COPY_SECONDS, DELIVERIES_REQUEUED, MESSAGES_RECEIVED, PARSE_FAILURES,
+        ROWS_PARSED, ROWS_WRITTEN, WRITER_QUEUE_DEPTH, WRITER_RECONNECTS, WRITER_UP,
+    },
+    rules::Rules,
+    shutdown,
+    timestamp::TimestampParser,
+};
 use regex::Regex;
-use tokio::sync::{mpsc, Notify};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, sleep_until, timeout_at, Instant},
+};
 use tokio_postgres::{
     binary_copy::BinaryCopyInWriter,
     connect,
+    error::SqlState,
     types::{ToSql, Type},
-    NoTls,
+    Client, NoTls,
 };
-use tracing::{info, metadata};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, metadata, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+/// How often extraction rule match rates are logged.
+const RULE_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 
 pub struct LogRow {
     time: chrono::DateTime<chrono::Utc>,
-    data: serde_json::Map<String, serde_json::Value>,
+    ingest_time: chrono::DateTime<chrono::Utc>,
+    data: serde_json::Value,
     level: String,
+    source: Option<String>,
     words: Vec<String>,
 }
 
 impl LogRow {
-    pub fn new(data: &serde_json::Map<String, serde_json::Value>) -> Self {
+    pub fn new(
+        data: &serde_json::Map<String, serde_json::Value>,
+        source: Option<&str>,
+        timestamps: &TimestampParser,
+    ) -> Self {
+        let ingest_time = chrono::offset::Utc::now();
         let mut words = HashSet::new();
         let mut try_words: Vec<serde_json::Value> = Vec::new();
         let mut final_data: serde_json::Map<String, serde_json::Value> = data.clone();
-        let level = data
-            .get("level")
-            .unwrap_or(&serde_json::Value::String("INFO".to_string()))
-            .as_str()
-            .unwrap()
-            .to_string();
+        let time = timestamps.extract(&mut final_data).unwrap_or(ingest_time);
+        let level = match data.get("level") {
+            Some(serde_json::Value::String(level)) => level.clone(),
+            Some(serde_json::Value::Null) | None => "INFO".to_string(),
+            Some(level) => level.to_string(),
+        };
         if !level.is_empty() {
             final_data.remove_entry("level");
         }
         try_words.push(final_data.to_owned().into());
-        loop {
-            let maybe_v = try_words.pop();
-            if maybe_v.is_none() {
-                break;
-            }
-            let value = maybe_v.unwrap();
-            let try_str = value.as_str();
-            if try_str.is_some() {
+        while let Some(value) = try_words.pop() {
+            if let Some(try_str) = value.as_str() {
                 lazy_static! {
                     static ref RE: Regex = Regex::new(r"[\w]+([-_][\w]+)*").unwrap();
                 };
-                for cap in RE.captures_iter(try_str.unwrap()) {
+                for cap in RE.captures_iter(try_str) {
                     words.insert(cap.get(0).unwrap().as_str().to_string());
                 }
                 continue;
             }
-            let try_array = value.as_array();
-            if try_array.is_some() {
-                for val in try_array.unwrap() {
+            if let Some(try_array) = value.as_array() {
+                for val in try_array {
                     try_words.push(val.to_owned());
                 }
                 continue;
             }
-            let try_nested = value.as_object();
-            if try_nested.is_some() {
-                for k in try_nested.unwrap().keys() {
+            if let Some(try_nested) = value.as_object() {
+                for k in try_nested.keys() {
                     words.insert(k.clone());
                 }
-                for val in try_nested.unwrap().values() {
+                for val in try_nested.values() {
                     try_words.push(val.to_owned());
                 }
             }
         }
         Self {
-            time: chrono::offset::Utc::now(),
-            data: final_data,
-            level: level,
+            time,
+            ingest_time,
+            data: final_data.into(),
+            level,
+            source: source.map(str::to_owned),
             words: words.into_iter().collect(),
         }
     }
 }
+/// A payload that could not be turned into rows, kept in `dead_letters` with the reason.
+#[derive(Debug)]
+pub struct DeadLetter {
+    source: Option<String>,
+    reason: String,
+    payload: Vec<u8>,
+}
+
+impl DeadLetter {
+    /// `kind` labels the failure in metrics, `detail` completes it in the table.
+    pub fn new(kind: &str, detail: impl Display, payload: Vec<u8>, source: Option<&str>) -> Self {
+        PARSE_FAILURES.with_label_values(&[kind]).inc();
+        warn!("dead letter from {:?}: {}: {}", source, kind, detail);
+        Self {
+            source: source.map(str::to_owned),
+            reason: format!("{}: {}", kind, detail),
+            payload,
+        }
+    }
+}
+
+/// Turn a message into rows, setting aside what cannot be parsed. JSON messages are
+/// expected to be arrays of objects, those of other formats to hold one log per line.
+fn parse_message(
+    content: Vec<u8>,
+    format: Format,
+    source: Option<&str>,
+    timestamps: &TimestampParser,
+    rules: &Rules,
+) -> (Vec<LogRow>, Vec<DeadLetter>) {
+    let text = match std::str::from_utf8(&content) {
+        Ok(text) => text,
+        Err(error) => {
+            return (
+                vec![],
+                vec![DeadLetter::new("invalid_utf8", error, content, source)],
+            )
+        }
+    };
+    if format != Format::Json {
+        return parse_lines(text, format, source, timestamps, rules);
+    }
+    let elements = match serde_json::from_str(text) {
+        Ok(serde_json::Value::Array(elements)) => elements,
+        Ok(other) => {
+            let detail = format!("expected an array of objects, got {}", json_type(&other));
+            return (
+                vec![],
+                vec![DeadLetter::new("not_array", detail, content, source)],
+            );
+        }
+        Err(error) => {
+            return (
+                vec![],
+                vec![DeadLetter::new("invalid_json", error, content, source)],
+            )
+        }
+    };
+    let mut rows = Vec::with_capacity(elements.len());
+    let mut dead_letters = Vec::new();
+    for (index, element) in elements.into_iter().enumerate() {
+        match element {
+            serde_json::Value::Object(mut data) => {
+                rules.apply(&mut data, timestamps);
+                rows.push(LogRow::new(&data, source, timestamps))
+            }
+            other => {
+                let detail = format!("element {} is {}, not an object", index, json_type(&other));
+                let payload = other.to_string().into_bytes();
+                dead_letters.push(DeadLetter::new("not_object", detail, payload, source));
+            }
+        }
+    }
+    (rows, dead_letters)
+}
+
+fn parse_lines(
+    text: &str,
+    format: Format,
+    source: Option<&str>,
+    timestamps: &TimestampParser,
+    rules: &Rules,
+) -> (Vec<LogRow>, Vec<DeadLetter>) {
+    let mut rows = Vec::new();
+    let mut dead_letters = Vec::new();
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        match format.parse(line) {
+            Ok(mut data) => {
+                rules.apply(&mut data, timestamps);
+                rows.push(LogRow::new(&data, source, timestamps))
+            }
+            Err(error) => {
+                let kind = format!("invalid_{}", format.name());
+                let payload = line.as_bytes().to_vec();
+                dead_letters.push(DeadLetter::new(&kind, error, payload, source));
+            }
+        }
+    }
+    (rows, dead_letters)
+}
+
+fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+/// The rows of one AMQP message, acknowledged once the transaction writing them commits.
+pub struct Delivery {
+    rows: Vec<LogRow>,
+    /// Size of the message, counted towards the batch size limit.
+    bytes: usize,
+    dead_letters: Vec<DeadLetter>,
+    channel: Channel,
+    delivery_tag: u64,
+}
+
+impl Delivery {
+    async fn ack(&self) {
+        let args = BasicAckArguments::new(self.delivery_tag, false);
+        if let Err(error) = self.channel.basic_ack(args).await {
+            // the broker redelivers it, its rows get written twice
+            warn!("cannot ack delivery {}: {}", self.delivery_tag, error);
+        }
+    }
+
+    /// Hand the message back to the broker, to be delivered again.
+    async fn requeue(&self) {
+        DELIVERIES_REQUEUED.inc();
+        let args = BasicNackArguments::new(self.delivery_tag, false, true);
+        if let Err(error) = self.channel.basic_nack(args).await {
+            // the broker redelivers it anyway once the channel closes
+            warn!("cannot requeue delivery {}: {}", self.delivery_tag, error);
+        }
+    }
+}
+
+impl LogRow {
+    /// The row as stored, for dead letters.
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "time": self.time.to_rfc3339(),
+            "ingest_time": self.ingest_time.to_rfc3339(),
+            "level": self.level,
+            "source": self.source,
+            "logdata": self.data,
+        })
+    }
+}
+
 pub struct MyConsumer {
-    sender: mpsc::Sender<LogRow>,
+    sender: mpsc::Sender<Delivery>,
+    timestamps: Arc<TimestampParser>,
+    rules: Arc<Rules>,
+    config: Arc<ConsumerConfig>,
 }
 
 impl MyConsumer {
     /// Return a new consumer.
     ///
     /// See [Acknowledgement Modes](https://www.rabbitmq.com/consumers.html#acknowledgement-modes)
-    pub fn new(sender: mpsc::Sender<LogRow>) -> Self {
-        // Now we can execute a simple statement that just returns its parameter.
-        Self { sender }
+    pub fn new(
+        sender: mpsc::Sender<Delivery>,
+        timestamps: Arc<TimestampParser>,
+        rules: Arc<Rules>,
+        config: Arc<ConsumerConfig>,
+    ) -> Self {
+        Self {
+            sender,
+            timestamps,
+            rules,
+            config,
+        }
     }
 }
 
 impl BlockingConsumer for MyConsumer {
     fn consume(
         &mut self,
-        _channel: &Channel,
-        _deliver: Deliver,
-        _basic_properties: BasicProperties,
+        channel: &Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
         content: Vec<u8>,
     ) {
-        let utf8_content = String::from_utf8(content).unwrap_or("{}".to_string());
-        let mut deser_res = serde_json::from_str(&utf8_content.as_str());
-        if deser_res.is_err() {
-            deser_res = serde_json::Value::from_str("[]");
-        }
-        for row in deser_res.unwrap().as_array().unwrap() {
-            let log = LogRow::new(row.as_object().unwrap());
-            self.sender.blocking_send(log).unwrap();
-        }
-        // ack explicitly if manual ack
-        /*
-        if !self.no_ack {
-            info!("ack to delivery {} on channel {}", deliver, channel);
-            let args = BasicAckArguments::new(deliver.delivery_tag(), false);
-            channel.basic_ack(args).unwrap();
+        // producers tag batches with their app id, fall back on the routing key
+        let source = basic_properties
+            .app_id()
+            .map(String::as_str)
+            .or(Some(deliver.routing_key().as_str()))
+            .filter(|source| !source.is_empty());
+        MESSAGES_RECEIVED.inc();
+        let bytes = content.len();
+        let format = self.config.format_of(deliver.routing_key());
+        let (rows, dead_letters) =
+            parse_message(content, format, source, &self.timestamps, &self.rules);
+        ROWS_PARSED.inc_by(rows.len() as u64);
+        // acked by the writer after commit, so nothing is lost if it fails before
+        let delivery = Delivery {
+            rows,
+            bytes,
+            dead_letters,
+            channel: channel.clone(),
+            delivery_tag: deliver.delivery_tag(),
+        };
+        if self.sender.blocking_send(delivery).is_err() {
+            // writers are gone, the broker delivers the message again to the next consumer
+            warn!(
+                "writers stopped, leaving delivery {} unacked",
+                deliver.delivery_tag()
+            );
         }
-        */
     }
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 24)]
-async fn main() {
+fn main() {
     // construct a subscriber that prints formatted traces to stdout
     // global subscriber with log level according to RUST_LOG
     tracing_subscriber::registry()
@@ -142,125 +340,498 @@ async fn main() {
         .try_init()
         .ok();
 
-    // open a connection to RabbitMQ server
+    let config = ConsumerConfig::load().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(2);
+    });
+    let rules = match &config.consumer.rules_file {
+        Some(path) => Rules::load(path).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(2);
+        }),
+        None => Rules::default(),
+    };
+    let drained = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.consumer.worker_threads)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(config, rules));
+    if !drained {
+        std::process::exit(1);
+    }
+}
 
-    // open a channel on the connection
-    // declare a server-named transient queue
+/// Consume until asked to stop, then return whether everything received was written in time.
+async fn run(config: ConsumerConfig, rules: Rules) -> bool {
+    let config = Arc::new(config);
+    let timestamps = Arc::new(TimestampParser::new(
+        config.consumer.time_fields.clone(),
+        config.consumer.time_formats.clone(),
+    ));
+    let rules = Arc::new(rules);
+    let shutdown = shutdown::on_signal();
+    // cancelled once no connection consumes anymore, for writers to finish the queued messages
+    let stopped = CancellationToken::new();
 
-    // bind the queue to exchange
-    //let rounting_key = "amqprs.example";
-    //let exchange_name = "amq.topic";
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..config.consumer.writers)
+        .map(|_| mpsc::channel(config.consumer.writer_queue))
+        .unzip();
 
-    // The connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    //////////////////////////////////////////////////////////////////////////////
-    // start consumer, auto ack
+    let mut consumers = Vec::new();
+    for i in 0..config.consumer.connections {
+        let writer = config.writer_of(i);
+        info!("AMQP connection {} feeds writer {}", i, writer);
+        let sender = senders[writer].clone();
+        let timestamps = timestamps.clone();
+        let rules = rules.clone();
+        let config = config.clone();
+        let shutdown = shutdown.clone();
+        consumers.push(tokio::spawn(async move {
+            let mut delay = config.consumer.retry_initial_delay();
+            loop {
+                let consumer = MyConsumer::new(
+                    sender.clone(),
+                    timestamps.clone(),
+                    rules.clone(),
+                    config.clone(),
+                );
+                let started = tokio::select! {
+                    started = start_consuming(i, &config, consumer) => started,
+                    _ = shutdown.cancelled() => return None,
+                };
+                let error = match started {
+                    Ok((connection, channel, consumer_tag)) => {
+                        delay = config.consumer.retry_initial_delay();
+                        tokio::select! {
+                            _ = connection.listen_network_io_failure() => {}
+                            _ = shutdown.cancelled() => {
+                                let args = BasicCancelArguments::new(&consumer_tag);
+                                if let Err(error) = channel.basic_cancel(args).await {
+                                    warn!("cannot stop consuming on connection {}: {}", i, error);
+                                }
+                                // kept open until the writers acked what it delivered
+                                return Some((connection, channel));
+                            }
+                        }
+                        // its unacked messages are delivered again
+                        "connection lost".to_owned()
+                    }
+                    Err(error) => error.to_string(),
+                };
+                AMQP_RECONNECTS.inc();
+                warn!(
+                    "AMQP connection {} reconnecting in {:?}: {}",
+                    i, delay, error
+                );
+                tokio::select! {
+                    _ = sleep(delay) => {}
+                    _ = shutdown.cancelled() => return None,
+                }
+                delay = min(delay * 2, config.consumer.retry_max_delay());
+            }
+        }));
+    }
+    if let Some(listen) = config.consumer.metrics_address() {
+        tokio::spawn(metrics::serve(listen));
+    }
+    if !rules.is_empty() {
+        // match rates are in the metrics too, logged for those not scraping them
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RULE_REPORT_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                for (name, matches, attempts) in rules.match_rates() {
+                    let rate = 100.0 * matches as f64 / attempts.max(1) as f64;
+                    info!(
+                        "rule {} matched {} of {} logs ({:.1}%)",
+                        name, matches, attempts, rate
+                    );
+                }
+            }
+        });
+    }
+    // sample how many messages wait for each writer, without keeping the channels open
+    let queues: Vec<_> = senders.iter().map(mpsc::Sender::downgrade).collect();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            for (index, queue) in queues.iter().enumerate() {
+                if let Some(sender) = queue.upgrade() {
+                    let depth = sender.max_capacity() - sender.capacity();
+                    WRITER_QUEUE_DEPTH
+                        .with_label_values(&[&index.to_string()])
+                        .set(depth as i64);
+                }
+            }
+        }
+    });
+    drop(senders);
 
-    let (tx, rx) = mpsc::channel(4096 * 4);
-    let (tx_2, rx_2) = mpsc::channel(4096 * 4);
-    let (tx_3, rx_3) = mpsc::channel(4096 * 4);
-    let (tx_4, rx_4) = mpsc::channel(4096 * 4);
+    let mut writers = Vec::new();
+    for (index, rx_handle) in receivers.into_iter().enumerate() {
+        let mut my_rx = rx_handle;
+        let config = config.clone();
+        let stopped = stopped.clone();
+        writers.push(tokio::spawn(async move {
+            let mut writer = Writer::new(index, config.clone());
+            let mut requeue_delay = config.consumer.retry_initial_delay();
+            // Start receiving messages, until none are left once consuming stopped
+            loop {
+                let delivery = tokio::select! {
+                    delivery = my_rx.recv() => delivery,
+                    _ = stopped.cancelled() => my_rx.try_recv().ok(),
+                };
+                let Some(delivery) = delivery else {
+                    break;
+                };
+                let started = Instant::now();
+                let linger_deadline = started + config.consumer.batch_linger();
+                let mut row_count = delivery.rows.len();
+                let mut byte_count = delivery.bytes;
+                let mut deliveries = vec![delivery];
+                // flush on whichever limit comes first
+                let reason = loop {
+                    if row_count >= config.consumer.batch_size {
+                        break "rows";
+                    }
+                    if byte_count >= config.consumer.batch_max_bytes {
+                        break "bytes";
+                    }
+                    let delivery = tokio::select! {
+                        delivery = my_rx.recv() => delivery,
+                        _ = sleep_until(linger_deadline) => break "linger",
+                        _ = stopped.cancelled() => my_rx.try_recv().ok(),
+                    };
+                    let Some(delivery) = delivery else {
+                        break "drain";
+                    };
+                    row_count += delivery.rows.len();
+                    byte_count += delivery.bytes;
+                    deliveries.push(delivery);
+                };
+                BATCH_FLUSHES.with_label_values(&[reason]).inc();
+                BATCH_ROWS.observe(row_count as f64);
+                BATCH_BYTES.observe(byte_count as f64);
+                BATCH_FILL_SECONDS.observe(started.elapsed().as_secs_f64());
+                debug!(
+                    "writer {} flushing {} rows of {} bytes on {}",
+                    index, row_count, byte_count, reason
+                );
+                if let Err(error) = writer.write(&deliveries).await {
+                    error!(
+                        "writer {} cannot store {} messages, requeueing them and pausing for {:?}: {}",
+                        index,
+                        deliveries.len(),
+                        requeue_delay,
+                        error
+                    );
+                    for delivery in &deliveries {
+                        delivery.requeue().await;
+                    }
+                    // the broker redelivers at once, so pause rather than fail again right away
+                    sleep(requeue_delay).await;
+                    requeue_delay = writer.backoff(requeue_delay);
+                    continue;
+                }
+                requeue_delay = config.consumer.retry_initial_delay();
+                for delivery in &deliveries {
+                    delivery.ack().await;
+                }
+            }
+        }));
+    }
+    info!("Consuming until SIGTERM or SIGINT");
+    shutdown.cancelled().await;
 
-    for i in 0..8 {
-        let mut tx2 = tx.clone();
-        if i % 4 == 0 {
-            tx2 = tx_2.clone();
+    let deadline = Instant::now() + config.consumer.shutdown_timeout();
+    let mut connections = Vec::new();
+    let cancelled = timeout_at(deadline, async {
+        for consumer in consumers {
+            if let Ok(Some(connection)) = consumer.await {
+                connections.push(connection);
+            }
         }
-        if i % 4 == 1 {
-            tx2 = tx_3.clone();
+    })
+    .await;
+    stopped.cancel();
+    let drained = cancelled.is_ok() && timeout_at(deadline, join_all(writers)).await.is_ok();
+    match drained {
+        true => info!("all received messages are written"),
+        false => error!(
+            "gave up writing after {:?}, unacked messages will be delivered again",
+            config.consumer.shutdown_timeout()
+        ),
+    }
+    for (connection, channel) in connections {
+        channel.close().await.ok();
+        connection.close().await.ok();
+    }
+    drained
+}
+/// Open AMQP connection `index` and start consuming the queue on it.
+async fn start_consuming(
+    index: usize,
+    config: &ConsumerConfig,
+    consumer: MyConsumer,
+) -> Result<(amqpConnection, Channel, String), amqprs::error::Error> {
+    let amqp = &config.amqp;
+    let connection = amqpConnection::open(&amqp.connection_args()).await?;
+    let channel = connection.open_channel(None).await?;
+    let (queue_name, _, _) = channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(&amqp.queue))
+        .await?
+        .unwrap();
+    // messages of other formats may be sent with their own routing keys
+    let routing_keys = std::iter::once(&amqp.routing_key).chain(config.consumer.formats.keys());
+    for routing_key in routing_keys {
+        channel
+            .queue_bind(QueueBindArguments::new(
+                &queue_name,
+                &amqp.exchange,
+                routing_key,
+            ))
+            .await?;
+    }
+    // bounds the messages held unacked by this connection
+    channel
+        .basic_qos(BasicQosArguments::new(0, config.consumer.prefetch, false))
+        .await?;
+    let consumer_tag = format!("logdog_consumer_{}", index);
+    let args = BasicConsumeArguments::new(&queue_name, &consumer_tag)
+        .manual_ack(true)
+        .finish();
+    channel.basic_consume_blocking(consumer, args).await?;
+    Ok((connection, channel, consumer_tag))
+}
+
+/// A database connection writing the batches of one channel, reconnecting when it is lost.
+struct Writer {
+    index: usize,
+    config: Arc<ConsumerConfig>,
+    client: Option<Client>,
+}
+
+impl Writer {
+    fn new(index: usize, config: Arc<ConsumerConfig>) -> Self {
+        WRITER_UP.with_label_values(&[&index.to_string()]).set(0);
+        Self {
+            index,
+            config,
+            client: None,
         }
-        if i % 5 == 2 {
-            tx2 = tx_4.clone();
+    }
+
+    fn label(&self) -> String {
+        self.index.to_string()
+    }
+
+    fn set_up(&self, up: bool) {
+        let gauge = WRITER_UP.with_label_values(&[&self.label()]);
+        if (gauge.get() == 1) != up {
+            match up {
+                true => info!("writer {} is connected", self.index),
+                false => warn!("writer {} lost its database connection", self.index),
+            }
+            gauge.set(up as i64);
         }
-        tokio::spawn(async move {
-            let connection = amqpConnection::open(&OpenConnectionArguments::new(
-                "localhost",
-                5672,
-                "guest",
-                "guest",
-            ))
-            .await
-            .unwrap();
-            let channel = connection.open_channel(None).await.unwrap();
-            let (queue_name, _, _) = channel
-                .queue_declare(QueueDeclareArguments::durable_client_named(
-                    "amqprs.examples.basic",
-                ))
-                //.queue_declare(QueueDeclareArguments::default())
-                .await
-                .unwrap()
-                .unwrap();
-            channel
-                .queue_bind(QueueBindArguments::new(
-                    &queue_name,
-                    "amq.topic",
-                    "amqprs.example",
-                ))
-                .await
-                .unwrap();
-            let args = BasicConsumeArguments::new(&queue_name, "basic_consumer")
-                .manual_ack(false)
-                .finish();
-            channel
-                .basic_consume_blocking(MyConsumer::new(tx2), args)
-                .await
-                .unwrap();
-            let guard = Notify::new();
-            guard.notified().await;
-        });
     }
 
-    for rx_handle in [rx, rx_2, rx_3, rx_4] {
-        let mut my_rx = rx_handle;
-        let _manager = tokio::spawn(async move {
-            // Establish a connection to the server
-            let (mut client, db_connect) =
-                connect("host=localhost user=postgres password=test", NoTls)
-                    .await
-                    .unwrap();
-            tokio::spawn(async move {
-                if let Err(e) = db_connect.await {
-                    eprintln!("connection error: {}", e);
+    /// Exponential backoff, starting again at the initial delay for each new failure.
+    fn backoff(&self, delay: Duration) -> Duration {
+        min(delay * 2, self.config.consumer.retry_max_delay())
+    }
+
+    /// The connection, opened again with backoff until the database answers.
+    async fn client(&mut self) -> &mut Client {
+        let mut delay = self.config.consumer.retry_initial_delay();
+        while self.client.as_ref().is_none_or(Client::is_closed) {
+            self.set_up(false);
+            match connect(&self.config.database.dsn, NoTls).await {
+                Ok((client, connection)) => {
+                    let index = self.index;
+                    tokio::spawn(async move {
+                        if let Err(e) = connection.await {
+                            error!("writer {} connection error: {}", index, e);
+                        }
+                    });
+                    self.client = Some(client);
+                    self.set_up(true);
                 }
-            });
-            // Start receiving messages
-            while let Some(cmd) = my_rx.recv().await {
-                let mut rows = Vec::new();
-                rows.push(cmd);
-                for _i in 0..19999 {
-                    let res = my_rx.try_recv();
-                    if res.is_err() {
-                        break;
-                    }
-                    rows.push(res.unwrap());
+                Err(error) => {
+                    WRITER_RECONNECTS.with_label_values(&[&self.label()]).inc();
+                    warn!(
+                        "writer {} cannot connect, retrying in {:?}: {}",
+                        self.index, delay, error
+                    );
+                    sleep(delay).await;
+                    delay = self.backoff(delay);
                 }
-                info!("{}", rows.len());
-                let transaction = client.transaction().await.unwrap();
-                let sink = transaction
-                    .copy_in("COPY logs (time, logdata, level, words) FROM STDIN BINARY")
-                    .await
-                    .unwrap();
-                let writer = BinaryCopyInWriter::new(
-                    sink,
-                    &[Type::TIMESTAMPTZ, Type::JSONB, Type::TEXT, Type::TEXT_ARRAY],
-                );
-                pin_mut!(writer);
-                for log in rows {
-                    let mut row: Vec<&'_ (dyn ToSql + Sync)> = Vec::new();
-                    let val = serde_json::Value::from(log.data);
-                    row.push(&log.time);
-                    row.push(&val);
-                    row.push(&log.level);
-                    row.push(&log.words);
-                    let _num_written = writer.as_mut().write(&row).await.unwrap();
+            }
+        }
+        self.client.as_mut().unwrap()
+    }
+
+    /// Write, retrying for as long as the failure looks like the database being unavailable.
+    async fn attempt(
+        &mut self,
+        rows: &[&LogRow],
+        dead_letters: &[&DeadLetter],
+    ) -> Result<(), tokio_postgres::Error> {
+        let mut delay = self.config.consumer.retry_initial_delay();
+        loop {
+            let client = self.client().await;
+            let copy_started = Instant::now();
+            let result = write_batch(client, rows, dead_letters).await;
+            COPY_SECONDS.observe(copy_started.elapsed().as_secs_f64());
+            let error = match result {
+                Ok(()) => {
+                    ROWS_WRITTEN.inc_by(rows.len() as u64);
+                    return Ok(());
                 }
-                writer.finish().await.unwrap();
-                transaction.commit().await.unwrap();
+                Err(error) => error,
+            };
+            if !client.is_closed() && !is_transient(&error) {
+                return Err(error);
             }
-        });
+            BATCH_RETRIES.with_label_values(&[&self.label()]).inc();
+            warn!(
+                "writer {} failed to write {} rows, retrying in {:?}: {}",
+                self.index,
+                rows.len(),
+                delay,
+                error
+            );
+            sleep(delay).await;
+            delay = self.backoff(delay);
+        }
+    }
+
+    /// Write the rows and dead letters of deliveries, which can be acked once this succeeds.
+    ///
+    /// Rows the database rejects are found by splitting the batch in halves until the
+    /// failing rows are alone, and stored as dead letters instead. Errors that would reject
+    /// any row, such as a missing column, are returned at once, and so is failing to store
+    /// the dead letters: some rows are then in neither table and the deliveries must be
+    /// consumed again, their other rows being written twice.
+    async fn write(&mut self, deliveries: &[Delivery]) -> Result<(), tokio_postgres::Error> {
+        let rows: Vec<&LogRow> = deliveries.iter().flat_map(|d| &d.rows).collect();
+        let mut dead_letters: Vec<&DeadLetter> =
+            deliveries.iter().flat_map(|d| &d.dead_letters).collect();
+        let error = match self.attempt(&rows, &dead_letters).await {
+            Ok(()) => return Ok(()),
+            Err(error) if rejects_every_row(&error) => return Err(error),
+            Err(error) => error,
+        };
+        warn!(
+            "writer {} had a batch of {} rows rejected, looking for the failing rows: {}",
+            self.index,
+            rows.len(),
+            error
+        );
+        let mut rejected = Vec::new();
+        let mut ranges = vec![(0, rows.len())];
+        while let Some((start, end)) = ranges.pop() {
+            let error = match self.attempt(&rows[start..end], &[]).await {
+                Ok(()) => continue,
+                Err(error) if rejects_every_row(&error) => return Err(error),
+                Err(error) => error,
+            };
+            if end - start == 1 {
+                let log = rows[start];
+                rejected.push(DeadLetter::new(
+                    "write_error",
+                    error,
+                    log.to_json().to_string().into_bytes(),
+                    log.source.as_deref(),
+                ));
+                continue;
+            }
+            let middle = start + (end - start) / 2;
+            ranges.push((middle, end));
+            ranges.push((start, middle));
+        }
+        dead_letters.extend(&rejected);
+        self.attempt(&[], &dead_letters).await
+    }
+}
+
+/// Failures that have nothing to do with the rows written, so that splitting the batch to
+/// isolate rejected rows would only fail the same way: a table or column missing, as
+/// before the database is migrated, a function missing, or privileges and read only mode.
+fn rejects_every_row(error: &tokio_postgres::Error) -> bool {
+    [
+        SqlState::UNDEFINED_TABLE,
+        SqlState::UNDEFINED_COLUMN,
+        SqlState::UNDEFINED_FUNCTION,
+        SqlState::INSUFFICIENT_PRIVILEGE,
+        SqlState::READ_ONLY_SQL_TRANSACTION,
+    ]
+    .iter()
+    .any(|code| error.code() == Some(code))
+}
+
+/// Failures worth retrying the same batch for: lost connections and SQLSTATE classes 08
+/// (connection exception), 40 (transaction rollback), 53 (insufficient resources) and 57
+/// (operator intervention, such as a restart).
+fn is_transient(error: &tokio_postgres::Error) -> bool {
+    if error.is_closed() {
+        return true;
+    }
+    match error.code() {
+        Some(code) => ["08", "40", "53", "57"]
+            .iter()
+            .any(|class| code.code().starts_with(class)),
+        None => false,
+    }
+}
+
+/// Write rows with a binary COPY, and dead letters, in one transaction.
+async fn write_batch(
+    client: &mut Client,
+    rows: &[&LogRow],
+    dead_letters: &[&DeadLetter],
+) -> Result<(), tokio_postgres::Error> {
+    let transaction = client.transaction().await?;
+    let sink = transaction
+        .copy_in("COPY logs (time, ingest_time, logdata, level, source, words) FROM STDIN BINARY")
+        .await?;
+    let writer = BinaryCopyInWriter::new(
+        sink,
+        &[
+            Type::TIMESTAMPTZ,
+            Type::TIMESTAMPTZ,
+            Type::JSONB,
+            Type::TEXT,
+            Type::TEXT,
+            Type::TEXT_ARRAY,
+        ],
+    );
+    pin_mut!(writer);
+    for log in rows {
+        let row: [&'_ (dyn ToSql + Sync); 6] = [
+            &log.time,
+            &log.ingest_time,
+            &log.data,
+            &log.level,
+            &log.source,
+            &log.words,
+        ];
+        writer.as_mut().write(&row).await?;
+    }
+    writer.finish().await?;
+    if !dead_letters.is_empty() {
+        let sources: Vec<Option<&str>> = dead_letters.iter().map(|d| d.source.as_deref()).collect();
+        let reasons: Vec<&str> = dead_letters.iter().map(|d| d.reason.as_str()).collect();
+        let payloads: Vec<&[u8]> = dead_letters.iter().map(|d| d.payload.as_slice()).collect();
+        transaction
+            .execute(
+                "INSERT INTO dead_letters (time, source, reason, payload) SELECT now(), * FROM UNNEST($1::text[], $2::text[], $3::bytea[])",
+                &[&sources, &reasons, &payloads],
+            )
+            .await?;
     }
-    // consume forever
-    info!("Consuming forever");
-    let guard = Notify::new();
-    guard.notified().await;
+    transaction.commit().await
 }
@@ -0,0 +1,541 @@
+//! Parsers turning lines of common log formats into the JSON objects stored as `logdata`,
+//! with numbers, times and levels extracted as such rather than left in the text.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Format of the lines a producer reads, or of the messages sent with a routing key.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// JSON objects, one per line for the producer or as an array in a message.
+    Json,
+    /// `key=value` pairs.
+    Logfmt,
+    /// RFC 5424 or RFC 3164 syslog lines, told apart by their header.
+    Syslog,
+    /// Common or combined log format of access logs, as written by nginx or Apache.
+    Clf,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "logfmt" => Ok(Format::Logfmt),
+            "syslog" => Ok(Format::Syslog),
+            "clf" => Ok(Format::Clf),
+            _ => Err(format!(
+                "unknown format {:?}, expected json, logfmt, syslog or clf",
+                s
+            )),
+        }
+    }
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Logfmt => "logfmt",
+            Format::Syslog => "syslog",
+            Format::Clf => "clf",
+        }
+    }
+
+    /// The object a line stands for, or why it is not a line of this format.
+    pub fn parse(&self, line: &str) -> Result<Map<String, Value>, String> {
+        match self {
+            Format::Json => match serde_json::from_str(line) {
+                Ok(Value::Object(data)) => Ok(data),
+                Ok(_) => Err("not a JSON object".to_owned()),
+                Err(error) => Err(error.to_string()),
+            },
+            Format::Logfmt => parse_logfmt(line),
+            Format::Syslog => parse_syslog(line),
+            Format::Clf => parse_clf(line),
+        }
+    }
+}
+
+/// A number or boolean when the text is one, otherwise the text.
+fn typed(text: &str) -> Value {
+    if let Ok(integer) = text.parse::<i64>() {
+        return integer.into();
+    }
+    if let Some(number) = text
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+    {
+        return Value::Number(number);
+    }
+    match text {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => Value::String(text.to_owned()),
+    }
+}
+
+/// Read a double quoted string at the start of `text`, returning it unescaped and what follows.
+fn quoted<'a>(text: &'a str, escapes: &[char]) -> Result<(String, &'a str), String> {
+    let mut chars = text
+        .strip_prefix('"')
+        .ok_or_else(|| "expected a quoted value".to_owned())?
+        .char_indices();
+    let mut value = String::new();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, escaped)) if escapes.contains(&escaped) => value.push(escaped),
+                // not an escape, kept as written
+                Some((_, other)) => {
+                    value.push('\\');
+                    value.push(other);
+                }
+                None => break,
+            },
+            '"' => return Ok((value, &text[index + 2..])),
+            c => value.push(c),
+        }
+    }
+    Err("unterminated quoted value".to_owned())
+}
+
+fn parse_logfmt(line: &str) -> Result<Map<String, Value>, String> {
+    if !line.contains('=') {
+        return Err("no key=value pair".to_owned());
+    }
+    let mut data = Map::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        if key.is_empty() {
+            return Err(format!("missing key before {:?}", rest));
+        }
+        rest = &rest[key_end..];
+        let value = match rest.strip_prefix('=') {
+            Some(after) if after.starts_with('"') => {
+                let (value, after) = quoted(after, &['"', '\\'])?;
+                rest = after;
+                Value::String(value)
+            }
+            Some(after) => {
+                let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                rest = &after[end..];
+                typed(&after[..end])
+            }
+            // a key alone is a flag
+            None => Value::Bool(true),
+        };
+        data.insert(key.to_owned(), value);
+        rest = rest.trim_start();
+    }
+    Ok(data)
+}
+
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+lazy_static! {
+    static ref PRI: Regex = Regex::new(r"^<(\d{1,3})>").unwrap();
+    /// `Mmm dd hh:mm:ss host rest`, the RFC 3164 header.
+    static ref BSD_HEADER: Regex =
+        Regex::new(r"^([A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}) (\S+) ?(.*)$").unwrap();
+    /// `app[pid]: message`
+    static ref BSD_TAG: Regex = Regex::new(r"^([^\s\[\]:]+)(?:\[([^\]]*)\])?: ?(.*)$").unwrap();
+}
+
+fn syslog_level(severity: usize) -> &'static str {
+    match severity {
+        0..=2 => "CRITICAL",
+        3 => "ERROR",
+        4 => "WARNING",
+        7 => "DEBUG",
+        _ => "INFO",
+    }
+}
+
+/// Syslog lines, with or without the `<PRI>` of the network protocol, as files keep them
+/// without it.
+fn parse_syslog(line: &str) -> Result<Map<String, Value>, String> {
+    let mut data = Map::new();
+    let mut rest = line;
+    if let Some(captures) = PRI.captures(line) {
+        let pri: usize = captures[1].parse().unwrap();
+        let (facility, severity) = (pri / 8, pri % 8);
+        let facility = FACILITIES
+            .get(facility)
+            .ok_or_else(|| format!("invalid priority {}", pri))?;
+        data.insert("facility".to_owned(), (*facility).into());
+        data.insert("severity".to_owned(), SEVERITIES[severity].into());
+        data.insert("level".to_owned(), syslog_level(severity).into());
+        rest = &line[captures[0].len()..];
+        if let Some(after) = rest.strip_prefix("1 ") {
+            parse_rfc5424(after, &mut data)?;
+            return Ok(data);
+        }
+    }
+    match BSD_HEADER.captures(rest) {
+        Some(header) => {
+            if let Some(time) = bsd_time(&header[1], Utc::now()) {
+                data.insert("time".to_owned(), time.to_rfc3339().into());
+            }
+            data.insert("hostname".to_owned(), header[2].into());
+            let message = header.get(3).map_or("", |message| message.as_str());
+            match BSD_TAG.captures(message) {
+                Some(tag) => {
+                    data.insert("app_name".to_owned(), tag[1].into());
+                    if let Some(pid) = tag.get(2) {
+                        data.insert("procid".to_owned(), typed(pid.as_str()));
+                    }
+                    data.insert("message".to_owned(), tag[3].into());
+                }
+                None => {
+                    data.insert("message".to_owned(), message.into());
+                }
+            }
+        }
+        // relays may strip the header, the priority is then enough to call it syslog
+        None if !data.is_empty() => {
+            data.insert("message".to_owned(), rest.into());
+        }
+        None => return Err("no syslog priority nor timestamp".to_owned()),
+    }
+    if !data.contains_key("level") {
+        data.insert("level".to_owned(), "INFO".into());
+    }
+    Ok(data)
+}
+
+/// RFC 3164 timestamps have no year nor zone: they are taken as UTC, in the last twelve months.
+fn bsd_time(text: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let parse = |year: i32| {
+        NaiveDateTime::parse_from_str(&format!("{} {}", year, text), "%Y %b %e %H:%M:%S")
+            .ok()
+            .map(|time| time.and_utc())
+    };
+    let time = parse(now.year())?;
+    match time > now + Duration::days(1) {
+        true => parse(now.year() - 1),
+        false => Some(time),
+    }
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, after the version.
+fn parse_rfc5424(text: &str, data: &mut Map<String, Value>) -> Result<(), String> {
+    let mut rest = text;
+    for name in ["time", "hostname", "app_name", "procid", "msgid"] {
+        let end = rest
+            .find(' ')
+            .ok_or_else(|| format!("missing {} or structured data", name))?;
+        let value = &rest[..end];
+        if value != "-" {
+            let value = match name {
+                "procid" => typed(value),
+                _ => value.into(),
+            };
+            data.insert(name.to_owned(), value);
+        }
+        rest = &rest[end + 1..];
+    }
+    match rest.strip_prefix('-') {
+        Some(after) => rest = after,
+        None => {
+            let mut elements = Map::new();
+            while let Some(after) = rest.strip_prefix('[') {
+                let id_end = after
+                    .find([' ', ']'])
+                    .ok_or_else(|| "unterminated structured data".to_owned())?;
+                let mut params = Map::new();
+                rest = &after[id_end..];
+                while let Some(after) = rest.strip_prefix(' ') {
+                    let (name, value) = after
+                        .split_once('=')
+                        .ok_or_else(|| "expected name=\"value\" in structured data".to_owned())?;
+                    let (value, after) = quoted(value, &['"', '\\', ']'])?;
+                    params.insert(name.to_owned(), value.into());
+                    rest = after;
+                }
+                rest = rest
+                    .strip_prefix(']')
+                    .ok_or_else(|| "unterminated structured data".to_owned())?;
+                elements.insert(after[..id_end].to_owned(), params.into());
+            }
+            if elements.is_empty() {
+                return Err("expected structured data or -".to_owned());
+            }
+            data.insert("structured_data".to_owned(), elements.into());
+        }
+    }
+    let message = rest.strip_prefix(' ').unwrap_or(rest);
+    let message = message.strip_prefix('\u{feff}').unwrap_or(message);
+    data.insert("message".to_owned(), message.into());
+    Ok(())
+}
+
+lazy_static! {
+    /// Common log format, then the referer and user agent of the combined one, then an
+    /// optional request time in seconds as nginx can append.
+    static ref CLF: Regex = Regex::new(concat!(
+        r#"^(\S+) (\S+) (\S+) \[([^\]]+)\] "((?:[^"\\]|\\.)*)" (\d{3}|-) (\d+|-)"#,
+        r#"(?: "((?:[^"\\]|\\.)*)" "((?:[^"\\]|\\.)*)")?(?: (\d+(?:\.\d+)?))?\s*$"#
+    ))
+    .unwrap();
+}
+
+fn parse_clf(line: &str) -> Result<Map<String, Value>, String> {
+    let captures = CLF
+        .captures(line)
+        .ok_or_else(|| "not a common or combined log format line".to_owned())?;
+    let mut data = Map::new();
+    let mut text = |name: &str, index: usize| {
+        if let Some(value) = captures.get(index).filter(|value| value.as_str() != "-") {
+            data.insert(name.to_owned(), value.as_str().into());
+        }
+    };
+    text("remote_addr", 1);
+    text("ident", 2);
+    text("user", 3);
+    text("request", 5);
+    text("referer", 8);
+    text("user_agent", 9);
+    let time = DateTime::parse_from_str(&captures[4], "%d/%b/%Y:%H:%M:%S %z")
+        .map_err(|error| format!("invalid time {:?}: {}", &captures[4], error))?;
+    data.insert("time".to_owned(), time.to_rfc3339().into());
+    let mut request = captures[5].splitn(3, ' ');
+    if let (Some(method), Some(path), Some(protocol)) =
+        (request.next(), request.next(), request.next())
+    {
+        data.insert("method".to_owned(), method.into());
+        data.insert("path".to_owned(), path.into());
+        data.insert("protocol".to_owned(), protocol.into());
+    }
+    let status: Option<i64> = captures[6].parse().ok();
+    if let Some(status) = status {
+        data.insert("status".to_owned(), status.into());
+    }
+    if let Ok(bytes) = captures[7].parse::<i64>() {
+        data.insert("bytes".to_owned(), bytes.into());
+    }
+    if let Some(seconds) = captures.get(10) {
+        data.insert("request_time".to_owned(), typed(seconds.as_str()));
+    }
+    let level = match status {
+        Some(500..) => "ERROR",
+        Some(400..) => "WARNING",
+        _ => "INFO",
+    };
+    data.insert("level".to_owned(), level.into());
+    data.insert("message".to_owned(), line.into());
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    fn parsed(format: Format, line: &str) -> Value {
+        Value::Object(format.parse(line).unwrap())
+    }
+
+    #[test]
+    fn logfmt_values_are_typed() {
+        assert_eq!(
+            parsed(
+                Format::Logfmt,
+                r#"level=info msg="request done \"ok\"" status=200 took=0.25 cached=false debug path=/a=b"#
+            ),
+            json!({
+                "level": "info",
+                "msg": "request done \"ok\"",
+                "status": 200,
+                "took": 0.25,
+                "cached": false,
+                "debug": true,
+                "path": "/a=b",
+            })
+        );
+        assert!(Format::Logfmt.parse("no pairs here").is_err());
+        assert!(Format::Logfmt.parse("msg=\"unterminated").is_err());
+        assert!(Format::Logfmt.parse("a=1 =2").is_err());
+    }
+
+    #[test]
+    fn rfc5424_syslog() {
+        assert_eq!(
+            parsed(
+                Format::Syslog,
+                r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3" eventSource="Appli\]cation"] An application event"#
+            ),
+            json!({
+                "facility": "local4",
+                "severity": "notice",
+                "level": "INFO",
+                "time": "2003-10-11T22:14:15.003Z",
+                "hostname": "mymachine.example.com",
+                "app_name": "evntslog",
+                "msgid": "ID47",
+                "structured_data": {
+                    "exampleSDID@32473": {"iut": "3", "eventSource": "Appli]cation"}
+                },
+                "message": "An application event",
+            })
+        );
+        assert_eq!(
+            parsed(Format::Syslog, "<11>1 - host app 42 - - \u{feff}disk full"),
+            json!({
+                "facility": "user",
+                "severity": "err",
+                "level": "ERROR",
+                "hostname": "host",
+                "app_name": "app",
+                "procid": 42,
+                "message": "disk full",
+            })
+        );
+        assert!(Format::Syslog
+            .parse("<13>1 2003-10-11T22:14:15Z host")
+            .is_err());
+        assert!(Format::Syslog
+            .parse("<13>1 - host app - - [id a=\"1\" msg")
+            .is_err());
+    }
+
+    #[test]
+    fn rfc3164_syslog() {
+        let data = Format::Syslog
+            .parse(
+                "<34>Oct 11 22:14:15 mymachine su[230]: 'su root' failed for lonvick on /dev/pts/8",
+            )
+            .unwrap();
+        assert_eq!(data["facility"], "auth");
+        assert_eq!(data["severity"], "crit");
+        assert_eq!(data["level"], "CRITICAL");
+        assert_eq!(data["hostname"], "mymachine");
+        assert_eq!(data["app_name"], "su");
+        assert_eq!(data["procid"], 230);
+        assert_eq!(
+            data["message"],
+            "'su root' failed for lonvick on /dev/pts/8"
+        );
+        assert!(data["time"].as_str().unwrap().contains("-10-11T22:14:15"));
+        // as files keep them, without priority
+        assert_eq!(
+            Format::Syslog
+                .parse("Feb  5 08:00:01 web cron: job done")
+                .unwrap()["level"],
+            "INFO"
+        );
+        // header stripped by a relay
+        assert_eq!(
+            parsed(Format::Syslog, "<12>just a message"),
+            json!({
+                "facility": "user",
+                "severity": "warning",
+                "level": "WARNING",
+                "message": "just a message",
+            })
+        );
+        assert!(Format::Syslog.parse("<999>Oct 11 22:14:15 host x").is_err());
+        assert!(Format::Syslog.parse("plain text").is_err());
+    }
+
+    #[test]
+    fn rfc3164_times_are_in_the_last_year() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 2, 12, 0, 0).unwrap();
+        assert_eq!(
+            bsd_time("Jan  2 08:00:00", now),
+            Some(Utc.with_ymd_and_hms(2024, 1, 2, 8, 0, 0).unwrap())
+        );
+        assert_eq!(
+            bsd_time("Dec 31 23:59:59", now),
+            Some(Utc.with_ymd_and_hms(2023, 12, 31, 23, 59, 59).unwrap())
+        );
+    }
+
+    #[test]
+    fn common_and_combined_log_formats() {
+        assert_eq!(
+            parsed(
+                Format::Clf,
+                r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#
+            ),
+            json!({
+                "remote_addr": "127.0.0.1",
+                "user": "frank",
+                "request": "GET /apache_pb.gif HTTP/1.0",
+                "time": "2000-10-10T13:55:36-07:00",
+                "method": "GET",
+                "path": "/apache_pb.gif",
+                "protocol": "HTTP/1.0",
+                "status": 200,
+                "bytes": 2326,
+                "level": "INFO",
+                "message": r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#,
+            })
+        );
+        let data = Format::Clf
+            .parse(r#"10.0.0.2 - - [10/Oct/2000:13:55:36 +0000] "POST /api HTTP/1.1" 503 - "https://example.com/" "curl/8.0" 0.042"#)
+            .unwrap();
+        assert_eq!(data["status"], 503);
+        assert_eq!(data["level"], "ERROR");
+        assert_eq!(data["referer"], "https://example.com/");
+        assert_eq!(data["user_agent"], "curl/8.0");
+        assert_eq!(data["request_time"], 0.042);
+        assert!(!data.contains_key("bytes"));
+        assert!(!data.contains_key("user"));
+        assert_eq!(
+            Format::Clf
+                .parse(r#"::1 - - [10/Oct/2000:13:55:36 +0000] "GET /missing HTTP/1.1" 404 0"#)
+                .unwrap()["level"],
+            "WARNING"
+        );
+        assert!(Format::Clf.parse("127.0.0.1 GET /").is_err());
+        assert!(Format::Clf
+            .parse(r#"127.0.0.1 - - [yesterday] "GET / HTTP/1.0" 200 1"#)
+            .is_err());
+    }
+}
@@ -0,0 +1,13 @@
+//! Shared pieces of the logdog ingest binaries.
+
+pub mod config;
+pub mod formats;
+pub mod metrics;
+pub mod multiline;
+pub mod publisher;
+pub mod record;
+pub mod rules;
+pub mod shutdown;
+pub mod spool;
+pub mod tail;
+pub mod timestamp;
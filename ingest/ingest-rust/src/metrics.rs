@@ -0,0 +1,187 @@
+//! Prometheus metrics of the ingest binaries, registered in the default registry and
+//! served over HTTP on `/metrics`.
+
+use std::net::SocketAddr;
+
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Histogram, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, TextEncoder,
+};
+use tracing::{error, info};
+
+lazy_static! {
+    pub static ref MESSAGES_RECEIVED: IntCounter = register_int_counter!(
+        "logdog_messages_received_total",
+        "AMQP messages received by the consumer"
+    )
+    .unwrap();
+    pub static ref ROWS_PARSED: IntCounter = register_int_counter!(
+        "logdog_rows_parsed_total",
+        "Log rows parsed from received messages"
+    )
+    .unwrap();
+    pub static ref ROWS_WRITTEN: IntCounter = register_int_counter!(
+        "logdog_rows_written_total",
+        "Log rows committed to the logs table"
+    )
+    .unwrap();
+    /// Duration of each transaction copying a batch, including failed ones.
+    pub static ref COPY_SECONDS: Histogram = register_histogram!(
+        "logdog_copy_seconds",
+        "Seconds taken by the COPY transaction of a batch",
+        exponential_buckets(0.001, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref WRITER_QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "logdog_writer_queue_depth",
+        "Messages waiting in the channel of each writer",
+        &["writer"]
+    )
+    .unwrap();
+    pub static ref AMQP_RECONNECTS: IntCounter = register_int_counter!(
+        "logdog_amqp_reconnects_total",
+        "AMQP connections lost or failing to open, then opened again"
+    )
+    .unwrap();
+    /// Payloads and elements that could not be turned into rows, by reason.
+    pub static ref PARSE_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "logdog_parse_failures_total",
+        "Messages or elements sent to the dead letters instead of the logs table",
+        &["reason"]
+    )
+    .unwrap();
+    /// 1 while a writer has a database connection, by writer index.
+    pub static ref WRITER_UP: IntGaugeVec = register_int_gauge_vec!(
+        "logdog_writer_up",
+        "Whether the writer is connected to the database",
+        &["writer"]
+    )
+    .unwrap();
+    pub static ref WRITER_RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "logdog_writer_reconnects_total",
+        "Failed attempts of writers to connect to the database",
+        &["writer"]
+    )
+    .unwrap();
+    pub static ref BATCH_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "logdog_batch_retries_total",
+        "Batches written again after a transient database failure",
+        &["writer"]
+    )
+    .unwrap();
+    /// Batches written by the consumer, by the limit that flushed them.
+    pub static ref BATCH_FLUSHES: IntCounterVec = register_int_counter_vec!(
+        "logdog_batch_flushes_total",
+        "Batches flushed, by reason: rows, bytes, linger or drain",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref BATCH_ROWS: Histogram = register_histogram!(
+        "logdog_batch_rows",
+        "Rows per written batch",
+        exponential_buckets(1.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref BATCH_BYTES: Histogram = register_histogram!(
+        "logdog_batch_bytes",
+        "Message bytes per written batch",
+        exponential_buckets(1024.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    /// Time from the first message of a batch to its flush.
+    pub static ref BATCH_FILL_SECONDS: Histogram = register_histogram!(
+        "logdog_batch_fill_seconds",
+        "Seconds between the first message of a batch and its flush",
+        exponential_buckets(0.001, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    /// Logs having the field a rule reads, by rule; divide matches by it for match rates.
+    pub static ref RULE_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        "logdog_rule_attempts_total",
+        "Logs an extraction rule was tried on",
+        &["rule"]
+    )
+    .unwrap();
+    pub static ref RULE_MATCHES: IntCounterVec = register_int_counter_vec!(
+        "logdog_rule_matches_total",
+        "Logs an extraction rule matched and took fields from",
+        &["rule"]
+    )
+    .unwrap();
+    pub static ref DELIVERIES_REQUEUED: IntCounter = register_int_counter!(
+        "logdog_deliveries_requeued_total",
+        "Messages handed back to the broker because neither their rows nor dead letters could be stored"
+    )
+    .unwrap();
+    pub static ref LINES_WRAPPED: IntCounter = register_int_counter!(
+        "logdog_lines_wrapped_total",
+        "Lines that were not JSON objects, published as the message of a record"
+    )
+    .unwrap();
+    pub static ref LINES_DROPPED: IntCounter = register_int_counter!(
+        "logdog_lines_dropped_total",
+        "Lines that were not JSON objects, left out by the producer"
+    )
+    .unwrap();
+    pub static ref BATCHES_PUBLISHED: IntCounter = register_int_counter!(
+        "logdog_batches_published_total",
+        "Batches published to the exchange by the producer"
+    )
+    .unwrap();
+    /// Batches on disk, waiting for the broker to be reachable again.
+    pub static ref SPOOLED_BATCHES: IntGauge = register_int_gauge!(
+        "logdog_spooled_batches",
+        "Batches waiting in the producer spool"
+    )
+    .unwrap();
+    pub static ref SPOOL_BYTES: IntGauge = register_int_gauge!(
+        "logdog_spool_bytes",
+        "Bytes of the batches waiting in the producer spool"
+    )
+    .unwrap();
+    pub static ref SPOOL_DROPPED_BATCHES: IntCounter = register_int_counter!(
+        "logdog_spool_dropped_batches_total",
+        "Oldest spooled batches dropped to stay within spool_max_bytes"
+    )
+    .unwrap();
+    pub static ref SYSLOG_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "logdog_syslog_messages_total",
+        "Syslog messages received, by transport",
+        &["transport"]
+    )
+    .unwrap();
+    pub static ref SYSLOG_CONNECTIONS: IntGauge = register_int_gauge!(
+        "logdog_syslog_connections",
+        "Open TCP connections of syslog senders"
+    )
+    .unwrap();
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut body).unwrap();
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        body,
+    )
+}
+
+/// Serve the registered metrics on `/metrics`.
+pub async fn serve(listen: SocketAddr) {
+    let app = Router::new().route("/metrics", get(metrics_handler));
+    let listener = match tokio::net::TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("cannot serve metrics on {}: {}", listen, err);
+            return;
+        }
+    };
+    info!("serving metrics on http://{}/metrics", listen);
+    if let Err(err) = axum::serve(listener, app).await {
+        error!("metrics server stopped: {}", err);
+    }
+}
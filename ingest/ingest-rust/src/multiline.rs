@@ -0,0 +1,104 @@
+//! Assembly of events spanning several lines, such as pretty printed JSON or stack traces,
+//! before they are batched.
+
+use std::{collections::HashMap, time::Instant};
+
+use regex::Regex;
+
+use crate::{config::MultilineConfig, tail::Line};
+
+/// Lines of an event still expecting more.
+struct Pending {
+    line: Line,
+    lines: usize,
+    updated: Instant,
+}
+
+/// Joins lines into events following `MultilineConfig`, separately for stdin and each file.
+pub struct Assembler {
+    start: Option<Regex>,
+    continuation: Option<Regex>,
+    config: MultilineConfig,
+    /// Keyed by device and inode, `None` for stdin.
+    pending: HashMap<Option<(u64, u64)>, Pending>,
+}
+
+impl Assembler {
+    /// The assembler of an enabled configuration, whose patterns were checked by validation.
+    pub fn new(config: &MultilineConfig) -> Self {
+        let compile = |pattern: &Option<String>| {
+            pattern
+                .as_deref()
+                .map(|pattern| Regex::new(pattern).expect("checked when loading the configuration"))
+        };
+        Assembler {
+            start: compile(&config.start),
+            continuation: compile(&config.continuation),
+            config: config.clone(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Add a line, pushing to `events` those it completes.
+    pub fn push(&mut self, line: Line, events: &mut Vec<Line>) {
+        let key = line
+            .position
+            .as_ref()
+            .map(|position| (position.dev, position.inode));
+        let joins = !self
+            .start
+            .as_ref()
+            .is_some_and(|start| start.is_match(&line.text))
+            && self
+                .continuation
+                .as_ref()
+                .is_none_or(|continuation| continuation.is_match(&line.text));
+        let pending = match self.pending.remove(&key) {
+            Some(mut pending)
+                if joins
+                    && pending.line.text.len() + 1 + line.text.len() <= self.config.max_bytes =>
+            {
+                pending.line.text.push('\n');
+                pending.line.text.push_str(&line.text);
+                pending.line.position = line.position;
+                pending.lines += 1;
+                pending.updated = Instant::now();
+                pending
+            }
+            previous => {
+                events.extend(previous.map(|pending| pending.line));
+                Pending {
+                    line,
+                    lines: 1,
+                    updated: Instant::now(),
+                }
+            }
+        };
+        if pending.lines >= self.config.max_lines
+            || pending.line.text.len() >= self.config.max_bytes
+        {
+            events.push(pending.line);
+        } else {
+            self.pending.insert(key, pending);
+        }
+    }
+
+    /// Push to `events` those which did not get a line for the configured timeout.
+    pub fn flush_expired(&mut self, events: &mut Vec<Line>) {
+        let timeout = self.config.timeout();
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.updated.elapsed() >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            events.push(self.pending.remove(&key).unwrap().line);
+        }
+    }
+
+    /// Push every event still expecting lines to `events`, as the input ends.
+    pub fn flush_all(&mut self, events: &mut Vec<Line>) {
+        events.extend(self.pending.drain().map(|(_, pending)| pending.line));
+    }
+}
@@ -1,13 +1,50 @@
-use amqprs::{
-    channel::{BasicPublishArguments, QueueBindArguments, QueueDeclareArguments},
-    connection::{Connection, OpenConnectionArguments},
-    BasicProperties,
+use logdog_rust::{
+    config::{NonJsonMode, ProducerConfig},
+    formats::Format,
+    metrics,
+    multiline::Assembler,
+    record, shutdown,
+    spool::{Spool, SpooledPublisher},
+    tail::{Checkpoint, Line, Tailer},
 };
+use tokio::{
+    sync::mpsc::{self, error::TryRecvError},
+    time::{sleep, timeout},
+};
+use tracing::{error, info};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use std::sync::mpsc::channel;
+use std::io::stdin;
 use std::time::Duration;
-use std::{io::stdin, thread::sleep};
+
+/// How often the checkpoint is written while tailing; it is always written before exiting.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Publish or spool lines as one batch, then record how far their files were read.
+async fn publish(
+    publisher: &mut SpooledPublisher,
+    checkpoint: &mut Option<Checkpoint>,
+    lines: Vec<Line>,
+    (format, non_json): (Format, NonJsonMode),
+) -> std::io::Result<()> {
+    let records: Vec<serde_json::Value> = lines
+        .iter()
+        .filter_map(|line| record::to_record(&line.text, format, non_json))
+        .collect();
+    if !records.is_empty() {
+        let payload = serde_json::to_vec(&records).expect("JSON values always serialize");
+        publisher.send(payload).await?;
+    }
+    if let Some(checkpoint) = checkpoint {
+        for position in lines.iter().filter_map(|line| line.position.as_ref()) {
+            checkpoint.advance(position);
+        }
+        if let Err(err) = checkpoint.save_every(CHECKPOINT_INTERVAL) {
+            error!("cannot save checkpoint: {}", err);
+        }
+    }
+    Ok(())
+}
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
 async fn main() {
@@ -19,82 +56,185 @@ async fn main() {
         .try_init()
         .ok();
 
-    // open a connection to RabbitMQ server
-    let connection = Connection::open(&OpenConnectionArguments::new(
-        "localhost",
-        5672,
-        "guest",
-        "guest",
-    ))
-    .await
-    .unwrap();
-
-    // open a channel on the connection
-    let amqp_channel = connection.open_channel(None).await.unwrap();
-
-    // declare a durable queue
-    let (queue_name, _, _) = amqp_channel
-        .queue_declare(QueueDeclareArguments::durable_client_named(
-            "amqprs.examples.basic",
-        ))
-        .await
-        .unwrap()
-        .unwrap();
+    let config = ProducerConfig::load().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(2);
+    });
+    let shutdown = shutdown::on_signal();
+    if let Some(listen) = config.producer.metrics_address() {
+        tokio::spawn(metrics::serve(listen));
+    }
 
-    // bind the queue to exchange
-    let rounting_key = "amqprs.example";
-    let exchange_name = "amq.topic";
-    amqp_channel
-        .queue_bind(QueueBindArguments::new(
-            &queue_name,
-            exchange_name,
-            rounting_key,
-        ))
-        .await
-        .unwrap();
+    let spool = Spool::open(&config.producer.spool_dir, config.producer.spool_max_bytes)
+        .unwrap_or_else(|error| {
+            eprintln!(
+                "cannot open spool {}: {}",
+                config.producer.spool_dir.display(),
+                error
+            );
+            std::process::exit(2);
+        });
+    let mut publisher = SpooledPublisher::new(
+        config.amqp.clone(),
+        config.producer.source(),
+        spool,
+        config.producer.retry_initial_delay(),
+        config.producer.retry_max_delay(),
+    );
+    let batch_lines = config.producer.batch_lines;
+    // bounded, so reading waits for publishing instead of holding a whole file in memory
+    let (tx, mut rx) = mpsc::channel(batch_lines * 16);
+    let mut checkpoint = None;
+    if config.producer.files.is_empty() {
+        // reading stdin blocks, so it gets its own thread, which ends with the input
+        std::thread::spawn(move || loop {
+            let mut text = String::new();
+            match stdin().read_line(&mut text) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    text.truncate(text.trim_end_matches(['\r', '\n']).len());
+                    let line = Line {
+                        text,
+                        position: None,
+                    };
+                    if tx.blocking_send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    } else {
+        let loaded = Checkpoint::load(&config.producer.checkpoint_file).unwrap_or_else(|error| {
+            eprintln!(
+                "cannot read checkpoint {}: {}",
+                config.producer.checkpoint_file.display(),
+                error
+            );
+            std::process::exit(2);
+        });
+        let mut tailer = Tailer::new(config.producer.files.clone(), &loaded);
+        checkpoint = Some(loaded);
+        let poll_interval = config.producer.poll_interval();
+        // files are polled, in a thread of their own like stdin, until publishing stops
+        std::thread::spawn(move || loop {
+            let lines = tailer.poll();
+            if lines.is_empty() {
+                if tx.is_closed() {
+                    break;
+                }
+                std::thread::sleep(poll_interval);
+            }
+            for line in lines {
+                if tx.blocking_send(line).is_err() {
+                    return;
+                }
+            }
+        });
+    }
 
-    let args = BasicPublishArguments::new(exchange_name, rounting_key);
-    let (tx, rx) = channel();
-    let _reader_manager = tokio::spawn(async move {
-        loop {
-            let mut line = String::new();
-            let read_line = stdin().read_line(&mut line);
-            if read_line.is_ok() {
-                let _res = tx.send(line);
+    let multiline = &config.producer.multiline;
+    let mut assembler = multiline.enabled().then(|| Assembler::new(multiline));
+    let parsing = (config.producer.format, config.producer.non_json);
+    let mut failed = false;
+    loop {
+        let mut lines = Vec::new();
+        let mut ended = false;
+        while lines.len() < batch_lines {
+            match rx.try_recv() {
+                Ok(line) => match &mut assembler {
+                    Some(assembler) => assembler.push(line, &mut lines),
+                    None => lines.push(line),
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    ended = true;
+                    break;
+                }
             }
         }
-    });
-    loop {
-        let mut final_str: String = "[".to_owned();
-        for i in 0..32 {
-            let read_event = rx.try_recv();
-            if read_event.is_err() {
+        if let Some(assembler) = &mut assembler {
+            match ended {
+                true => assembler.flush_all(&mut lines),
+                false => assembler.flush_expired(&mut lines),
+            }
+        }
+        if !lines.is_empty() {
+            if let Err(err) = publish(&mut publisher, &mut checkpoint, lines, parsing).await {
+                error!("cannot spool batch: {}", err);
+                failed = true;
                 break;
             }
-            let read_bytes = read_event.unwrap();
-            let mut format_prefix = ",";
-            if i == 0 {
-                format_prefix = "";
+        } else if ended {
+            info!("input ended");
+            break;
+        } else {
+            if let Err(err) = publisher.flush().await {
+                error!("cannot read spool: {}", err);
+            }
+            tokio::select! {
+                _ = sleep(Duration::from_millis(200)) => {}
+                _ = shutdown.cancelled() => break,
             }
-            final_str.push_str(format!("{}{}", format_prefix, read_bytes).as_str());
         }
-        final_str.push_str("]");
-        if final_str.len() > 2 {
-            amqp_channel
-                .basic_publish(
-                    BasicProperties::default(),
-                    final_str.as_bytes().to_vec(),
-                    args.clone(),
-                )
-                .await
-                .unwrap();
-        } else {
-            sleep(Duration::from_millis(200));
+        if shutdown.is_cancelled() {
+            break;
+        }
+    }
+
+    // publish what was read but not sent yet, the spool first
+    rx.close();
+    let drain = async {
+        publisher.flush().await?;
+        loop {
+            let mut lines = Vec::new();
+            while lines.len() < batch_lines {
+                match rx.try_recv() {
+                    Ok(line) => match &mut assembler {
+                        Some(assembler) => assembler.push(line, &mut lines),
+                        None => lines.push(line),
+                    },
+                    Err(_) => {
+                        // the input is over, so are the events being assembled
+                        if let Some(assembler) = &mut assembler {
+                            assembler.flush_all(&mut lines);
+                        }
+                        break;
+                    }
+                }
+            }
+            if lines.is_empty() {
+                return Ok::<(), std::io::Error>(());
+            }
+            publish(&mut publisher, &mut checkpoint, lines, parsing).await?;
+        }
+    };
+    let drained = match timeout(config.producer.shutdown_timeout(), drain).await {
+        Ok(Ok(())) => !failed,
+        Ok(Err(err)) => {
+            error!("cannot publish pending lines: {}", err);
+            false
         }
+        Err(_) => {
+            error!(
+                "gave up publishing pending lines after {:?}",
+                config.producer.shutdown_timeout()
+            );
+            false
+        }
+    };
+    if let Some(checkpoint) = &mut checkpoint {
+        if let Err(err) = checkpoint.save() {
+            error!("cannot save checkpoint: {}", err);
+        }
+    }
+    if publisher.spooled() > 0 {
+        info!(
+            "{} batches left in the spool, published on the next start",
+            publisher.spooled()
+        );
+    }
+    publisher.close().await;
+    if !drained {
+        std::process::exit(1);
     }
-    // explicitly close
-    /*
-    amqp_channel.close().await.unwrap();
-    connection.close().await.unwrap();
-    */
 }
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::future::join_all;
+use logdog_rust::{
+    config::PromConfig,
+    shutdown,
+    spool::{Spool, SpooledPublisher},
+};
+use serde_json::{json, Map, Value};
+use tokio::time::{interval, timeout, MissedTickBehavior};
+use tracing::{error, info, warn};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+/// One line of the text exposition format.
+#[derive(Debug, PartialEq)]
+struct Sample {
+    name: String,
+    labels: Map<String, Value>,
+    value: f64,
+    /// Milliseconds since the epoch, when the endpoint gives one.
+    timestamp: Option<i64>,
+}
+
+/// Parse a value of the exposition format, which allows `NaN` and `+Inf`/`-Inf`.
+fn parse_value(text: &str) -> Option<f64> {
+    match text {
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        _ => text.parse().ok(),
+    }
+}
+
+/// Parse `{name="value",...}` at the start of `text`, returning the labels and what follows.
+fn parse_labels(text: &str) -> Result<(Map<String, Value>, &str), String> {
+    let mut labels = Map::new();
+    let mut rest = text
+        .strip_prefix('{')
+        .ok_or_else(|| "expected {".to_owned())?;
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+        if let Some(after) = rest.strip_prefix('}') {
+            return Ok((labels, after));
+        }
+        let (name, after) = rest
+            .split_once('=')
+            .ok_or_else(|| "expected = after label name".to_owned())?;
+        let mut chars = after
+            .strip_prefix('"')
+            .ok_or_else(|| format!("expected quoted value for label {}", name.trim()))?
+            .char_indices();
+        let mut value = String::new();
+        let end = loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, escaped)) => value.push(escaped),
+                    None => return Err("unterminated label value".to_owned()),
+                },
+                Some((index, '"')) => break index,
+                Some((_, c)) => value.push(c),
+                None => return Err("unterminated label value".to_owned()),
+            }
+        };
+        labels.insert(name.trim().to_owned(), Value::String(value));
+        rest = &after[end + 2..];
+    }
+}
+
+fn parse_sample(line: &str) -> Result<Sample, String> {
+    let name_end = line.find(['{', ' ', '\t']).unwrap_or(line.len());
+    let name = &line[..name_end];
+    if name.is_empty() {
+        return Err("missing metric name".to_owned());
+    }
+    let (labels, rest) = match line[name_end..].starts_with('{') {
+        true => parse_labels(&line[name_end..])?,
+        false => (Map::new(), &line[name_end..]),
+    };
+    let mut fields = rest.split_whitespace();
+    let value = fields
+        .next()
+        .and_then(parse_value)
+        .ok_or_else(|| "missing or invalid value".to_owned())?;
+    let timestamp = match fields.next() {
+        Some(timestamp) => Some(
+            timestamp
+                .parse()
+                .map_err(|_| format!("invalid timestamp {}", timestamp))?,
+        ),
+        None => None,
+    };
+    Ok(Sample {
+        name: name.to_owned(),
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+/// Samples of a scrape, with the type declared for each metric family.
+fn parse_exposition(text: &str) -> (Vec<Sample>, HashMap<String, String>) {
+    let mut samples = Vec::new();
+    let mut types = HashMap::new();
+    for line in text.lines().map(str::trim) {
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut words = comment.split_whitespace();
+            if let (Some("TYPE"), Some(family), Some(kind)) =
+                (words.next(), words.next(), words.next())
+            {
+                types.insert(family.to_owned(), kind.to_owned());
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        match parse_sample(line) {
+            Ok(sample) => samples.push(sample),
+            Err(error) => warn!("skipping sample {:?}: {}", line, error),
+        }
+    }
+    (samples, types)
+}
+
+/// The declared type of a sample, looking up the family of `_bucket`, `_sum`, `_count`...
+fn sample_type<'a>(name: &str, types: &'a HashMap<String, String>) -> Option<&'a str> {
+    if let Some(kind) = types.get(name) {
+        return Some(kind);
+    }
+    ["_bucket", "_sum", "_count", "_total", "_created", "_info"]
+        .iter()
+        .filter_map(|suffix| name.strip_suffix(suffix))
+        .find_map(|family| types.get(family))
+        .map(String::as_str)
+}
+
+/// A sample as a log record. JSON has no NaN nor infinities, which are kept as strings.
+fn to_record(sample: Sample, kind: Option<&str>, target: &str, scraped_at: DateTime<Utc>) -> Value {
+    let time = sample
+        .timestamp
+        .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+        .unwrap_or(scraped_at);
+    let value = match serde_json::Number::from_f64(sample.value) {
+        Some(number) => Value::Number(number),
+        None if sample.value.is_nan() => Value::String("NaN".to_owned()),
+        None if sample.value > 0.0 => Value::String("+Inf".to_owned()),
+        None => Value::String("-Inf".to_owned()),
+    };
+    json!({
+        "time": time.to_rfc3339(),
+        "name": sample.name,
+        "labels": sample.labels,
+        "value": value,
+        "type": kind.unwrap_or("untyped"),
+        "target": target,
+    })
+}
+
+/// Scrape one target into records, with an `up` record telling whether it answered.
+async fn scrape(client: &reqwest::Client, target: &str) -> Vec<Value> {
+    let scraped_at = Utc::now();
+    let response = match client.get(target).send().await {
+        Ok(response) => response.error_for_status(),
+        Err(error) => Err(error),
+    };
+    let text = match response {
+        Ok(response) => response.text().await,
+        Err(error) => Err(error),
+    };
+    let up = Sample {
+        name: "up".to_owned(),
+        labels: Map::new(),
+        value: text.is_ok() as u8 as f64,
+        timestamp: None,
+    };
+    let mut records = vec![to_record(up, Some("gauge"), target, scraped_at)];
+    match text {
+        Ok(text) => {
+            let (samples, types) = parse_exposition(&text);
+            for sample in samples {
+                let kind = sample_type(&sample.name, &types);
+                records.push(to_record(sample, kind, target, scraped_at));
+            }
+        }
+        Err(error) => warn!("cannot scrape {}: {}", target, error),
+    }
+    records
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main() {
+    // construct a subscriber that prints formatted traces to stdout
+    // global subscriber with log level according to RUST_LOG
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(EnvFilter::from_default_env())
+        .try_init()
+        .ok();
+
+    let config = PromConfig::load().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(2);
+    });
+    let prom = &config.prom;
+    let shutdown = shutdown::on_signal();
+    let spool = Spool::open(&prom.spool_dir, prom.spool_max_bytes).unwrap_or_else(|error| {
+        eprintln!("cannot open spool {}: {}", prom.spool_dir.display(), error);
+        std::process::exit(2);
+    });
+    let mut publisher = SpooledPublisher::new(
+        config.amqp.clone(),
+        prom.source(),
+        spool,
+        prom.retry_initial_delay(),
+        prom.retry_max_delay(),
+    );
+    let client = reqwest::Client::builder()
+        .timeout(prom.timeout())
+        .build()
+        .unwrap_or_else(|error| {
+            eprintln!("cannot create HTTP client: {}", error);
+            std::process::exit(2);
+        });
+
+    let mut ticks = interval(prom.interval());
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut failed = false;
+    'scraping: loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown.cancelled() => break,
+        }
+        let scrapes = join_all(prom.targets.iter().map(|target| scrape(&client, target))).await;
+        let records: Vec<Value> = scrapes.into_iter().flatten().collect();
+        info!("publishing {} samples", records.len());
+        for batch in records.chunks(prom.batch_samples) {
+            let payload = serde_json::to_vec(batch).expect("JSON values always serialize");
+            if let Err(err) = publisher.send(payload).await {
+                error!("cannot spool batch: {}", err);
+                failed = true;
+                break 'scraping;
+            }
+        }
+    }
+
+    // samples spooled while the broker was away are published before exiting when possible
+    let drained = match timeout(prom.shutdown_timeout(), publisher.flush()).await {
+        Ok(Ok(())) => !failed,
+        Ok(Err(err)) => {
+            error!("cannot read spool: {}", err);
+            false
+        }
+        Err(_) => {
+            error!(
+                "gave up publishing spooled samples after {:?}",
+                prom.shutdown_timeout()
+            );
+            false
+        }
+    };
+    if publisher.spooled() > 0 {
+        info!(
+            "{} batches left in the spool, published on the next start",
+            publisher.spooled()
+        );
+    }
+    publisher.close().await;
+    if !drained {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPOSITION: &str = r#"
+# HELP http_requests_total Requests handled.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"}    3 1395066363000
+
+# TYPE request_seconds histogram
+request_seconds_bucket{le="0.05"} 24054
+request_seconds_bucket{le="+Inf"} 144320
+request_seconds_sum 53423
+request_seconds_count 144320
+msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9
+metric_without_timestamp_and_labels 12.47
+broken{code="200" 1
+"#;
+
+    #[test]
+    fn parses_samples_and_types() {
+        let (samples, types) = parse_exposition(EXPOSITION);
+        assert_eq!(samples.len(), 8);
+        assert_eq!(
+            samples[0],
+            Sample {
+                name: "http_requests_total".to_owned(),
+                labels: json!({"method": "post", "code": "200"})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+                value: 1027.0,
+                timestamp: Some(1395066363000),
+            }
+        );
+        assert_eq!(samples[1].value, 3.0);
+        assert_eq!(samples[3].labels["le"], "+Inf");
+        assert_eq!(
+            samples[6].labels,
+            *json!({"path": "C:\\DIR\\FILE.TXT", "error": "Cannot find file:\n\"FILE.TXT\""})
+                .as_object()
+                .unwrap()
+        );
+        assert_eq!(samples[6].value, 1.458255915e9);
+        assert_eq!(samples[7].labels, Map::new());
+        assert_eq!(samples[7].timestamp, None);
+        assert_eq!(sample_type("http_requests_total", &types), Some("counter"));
+        assert_eq!(
+            sample_type("request_seconds_bucket", &types),
+            Some("histogram")
+        );
+        assert_eq!(
+            sample_type("request_seconds_count", &types),
+            Some("histogram")
+        );
+        assert_eq!(
+            sample_type("metric_without_timestamp_and_labels", &types),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_samples() {
+        for line in [
+            "{code=\"200\"} 1",
+            "name",
+            "name{code=200} 1",
+            "name{code=\"200} 1",
+            "name{code} 1",
+            "name one",
+            "name 1 yesterday",
+        ] {
+            assert!(parse_sample(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn special_values_are_kept_as_strings() {
+        let scraped_at = Utc.timestamp_millis_opt(1_700_000_000_000).unwrap();
+        let record = |line: &str| {
+            to_record(
+                parse_sample(line).unwrap(),
+                Some("gauge"),
+                "http://host/metrics",
+                scraped_at,
+            )
+        };
+        assert_eq!(record("up NaN")["value"], "NaN");
+        assert_eq!(record("up +Inf")["value"], "+Inf");
+        assert_eq!(record("up -Inf")["value"], "-Inf");
+        assert_eq!(
+            record("up 1 1395066363000"),
+            json!({
+                "time": "2014-03-17T14:26:03+00:00",
+                "name": "up",
+                "labels": {},
+                "value": 1.0,
+                "type": "gauge",
+                "target": "http://host/metrics",
+            })
+        );
+        assert_eq!(record("up 0")["time"], scraped_at.to_rfc3339());
+    }
+}
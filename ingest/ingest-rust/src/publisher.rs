@@ -0,0 +1,181 @@
+//! Publishing of log batches to the AMQP exchange, shared by the producers.
+
+use std::{fmt, time::Duration};
+
+use amqprs::{
+    callbacks::ChannelCallback,
+    channel::{
+        BasicPublishArguments, Channel, ConfirmSelectArguments, QueueBindArguments,
+        QueueDeclareArguments,
+    },
+    connection::Connection,
+    error::Error,
+    Ack, BasicProperties, Cancel, CloseChannel, Nack, Return,
+};
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::config::AmqpConfig;
+
+/// Longest wait for the broker to confirm a batch.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a batch may not have reached the queue.
+#[derive(Debug)]
+pub enum PublishError {
+    Amqp(Error),
+    Nack,
+    Timeout,
+    Closed,
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PublishError::Amqp(err) => err.fmt(f),
+            PublishError::Nack => write!(f, "the broker refused the batch"),
+            PublishError::Timeout => {
+                write!(f, "the broker did not confirm within {:?}", CONFIRM_TIMEOUT)
+            }
+            PublishError::Closed => write!(f, "the channel closed before the broker confirmed"),
+        }
+    }
+}
+
+impl From<Error> for PublishError {
+    fn from(err: Error) -> Self {
+        PublishError::Amqp(err)
+    }
+}
+
+/// What the broker told of the batches published on the channel.
+enum Confirm {
+    /// Delivery tag, whether it covers every earlier tag too, and whether it is an ack.
+    Tag(u64, bool, bool),
+    Closed,
+}
+
+/// Forwards confirms and the closing of the channel to the publisher waiting for them.
+struct ConfirmCallback(mpsc::UnboundedSender<Confirm>);
+
+#[async_trait]
+impl ChannelCallback for ConfirmCallback {
+    async fn close(&mut self, _: &Channel, _: CloseChannel) -> Result<(), Error> {
+        self.0.send(Confirm::Closed).ok();
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _: &Channel, _: Cancel) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn flow(&mut self, _: &Channel, _: bool) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, _: &Channel, ack: Ack) {
+        self.0
+            .send(Confirm::Tag(ack.delivery_tag(), ack.mutiple(), true))
+            .ok();
+    }
+
+    async fn publish_nack(&mut self, _: &Channel, nack: Nack) {
+        self.0
+            .send(Confirm::Tag(nack.delivery_tag(), nack.multiple(), false))
+            .ok();
+    }
+
+    async fn publish_return(&mut self, _: &Channel, _: Return, _: BasicProperties, _: Vec<u8>) {}
+}
+
+/// A channel publishing JSON arrays of log records, the format the consumer reads, each
+/// confirmed by the broker before the next one.
+pub struct Publisher {
+    connection: Connection,
+    channel: Channel,
+    args: BasicPublishArguments,
+    properties: BasicProperties,
+    confirms: mpsc::UnboundedReceiver<Confirm>,
+    /// Delivery tag of the last batch published, counted by the broker from 1.
+    delivery_tag: u64,
+}
+
+impl Publisher {
+    /// Connect, make sure the queue exists and is bound, and tag batches with `source`.
+    pub async fn connect(amqp: &AmqpConfig, source: &str) -> Result<Self, Error> {
+        let connection = Connection::open(&amqp.connection_args()).await?;
+        let channel = connection.open_channel(None).await?;
+        let (sender, confirms) = mpsc::unbounded_channel();
+        channel.register_callback(ConfirmCallback(sender)).await?;
+        channel
+            .confirm_select(ConfirmSelectArguments::default())
+            .await?;
+        let (queue_name, _, _) = channel
+            .queue_declare(QueueDeclareArguments::durable_client_named(&amqp.queue))
+            .await?
+            .unwrap();
+        channel
+            .queue_bind(QueueBindArguments::new(
+                &queue_name,
+                &amqp.exchange,
+                &amqp.routing_key,
+            ))
+            .await?;
+        // tag every batch with where it comes from, so the consumer can fill `logs.source`
+        let properties = BasicProperties::default().with_app_id(source).finish();
+        Ok(Self {
+            connection,
+            channel,
+            args: BasicPublishArguments::new(&amqp.exchange, &amqp.routing_key),
+            properties,
+            confirms,
+            delivery_tag: 0,
+        })
+    }
+
+    /// Publish an already encoded batch and wait for the broker to confirm it.
+    pub async fn publish_payload(&mut self, payload: Vec<u8>) -> Result<(), PublishError> {
+        self.channel
+            .basic_publish(self.properties.clone(), payload, self.args.clone())
+            .await?;
+        self.delivery_tag += 1;
+        let tag = self.delivery_tag;
+        let confirmed = tokio::time::timeout(CONFIRM_TIMEOUT, async {
+            loop {
+                match self.confirms.recv().await {
+                    Some(Confirm::Tag(confirmed, multiple, ack))
+                        if confirmed == tag || (multiple && confirmed > tag) =>
+                    {
+                        return ack;
+                    }
+                    // confirms of earlier batches
+                    Some(Confirm::Tag(..)) => {}
+                    Some(Confirm::Closed) | None => return false,
+                }
+            }
+        })
+        .await;
+        match confirmed {
+            Ok(true) => Ok(()),
+            Ok(false) if self.channel.is_open() => Err(PublishError::Nack),
+            Ok(false) => Err(PublishError::Closed),
+            Err(_) => Err(PublishError::Timeout),
+        }
+    }
+
+    /// Publish records as one JSON array.
+    pub async fn publish(&mut self, records: &[serde_json::Value]) -> Result<(), PublishError> {
+        let payload = serde_json::to_vec(records).expect("JSON values always serialize");
+        self.publish_payload(payload).await
+    }
+
+    /// Whether the connection and channel are still usable.
+    pub fn is_open(&self) -> bool {
+        self.connection.is_open() && self.channel.is_open()
+    }
+
+    pub async fn close(self) {
+        self.channel.close().await.ok();
+        self.connection.close().await.ok();
+    }
+}
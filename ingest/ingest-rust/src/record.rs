@@ -0,0 +1,70 @@
+//! Validation of the lines read by the producer, turning each into the JSON object it publishes.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{
+    config::NonJsonMode,
+    formats::Format,
+    metrics::{LINES_DROPPED, LINES_WRAPPED},
+};
+
+/// Bytes at the start of a line searched for its level, enough to skip a timestamp.
+const LEVEL_PREFIX: usize = 64;
+
+lazy_static! {
+    static ref LEVEL: Regex = Regex::new(
+        r"(?i)\b(trace|debug|info|notice|warn|warning|err|error|crit|critical|fatal|panic|alert|emerg)\b"
+    )
+    .unwrap();
+}
+
+/// The level named near the start of a plain text line, `INFO` when there is none.
+pub fn guess_level(text: &str) -> &'static str {
+    let mut end = text.len().min(LEVEL_PREFIX);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let found = match LEVEL.find(&text[..end]) {
+        Some(found) => found.as_str().to_ascii_lowercase(),
+        None => return "INFO",
+    };
+    match found.as_str() {
+        "trace" => "TRACE",
+        "debug" => "DEBUG",
+        "warn" | "warning" => "WARNING",
+        "err" | "error" => "ERROR",
+        "crit" | "critical" | "fatal" | "panic" | "alert" | "emerg" => "CRITICAL",
+        _ => "INFO",
+    }
+}
+
+/// The record to publish for a line: the object parsed from it in `format`, otherwise
+/// what `mode` says. Blank lines give nothing.
+pub fn to_record(text: &str, format: Format, mode: NonJsonMode) -> Option<Value> {
+    if text.trim().is_empty() {
+        return None;
+    }
+    let error = match format.parse(text) {
+        Ok(data) => return Some(Value::Object(data)),
+        Err(error) => error,
+    };
+    match mode {
+        NonJsonMode::Wrap => {
+            LINES_WRAPPED.inc();
+            Some(json!({ "message": text, "level": guess_level(text) }))
+        }
+        NonJsonMode::Drop => {
+            LINES_DROPPED.inc();
+            warn!(
+                "dropping line that is not {}: {}: {:?}",
+                format.name(),
+                error,
+                text
+            );
+            None
+        }
+    }
+}
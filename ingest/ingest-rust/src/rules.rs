@@ -0,0 +1,497 @@
+//! Extraction rules, grok-like regexes run by the consumer on string fields of each log,
+//! whose captures become typed fields of `logdata`.
+//!
+//! A rule file holds `[[rules]]` with a `name`, the `field` read (`message` by default), a
+//! `pattern`, and the `types` of its captures. Patterns are regexes where `%{NAME}` stands
+//! for a named pattern, built in or from the `[patterns]` table, `%{NAME:field}` captures it
+//! into `field` and `%{NAME:field:type}` gives its type too. `(?P<field>...)` groups capture
+//! as well. A dotted field such as `http.method` is stored nested, like the server reads it.
+
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use lazy_static::lazy_static;
+use prometheus::IntCounter;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    config::ConfigError,
+    metrics::{RULE_ATTEMPTS, RULE_MATCHES},
+    timestamp::TimestampParser,
+};
+
+/// Patterns usable in every rule file.
+const BUILTIN_PATTERNS: [(&str, &str); 22] = [
+    ("WORD", r"\b\w+\b"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("INT", r"[+-]?\d+"),
+    ("NUMBER", r"[+-]?(?:\d+(?:\.\d*)?|\.\d+)(?:[eE][+-]?\d+)?"),
+    ("BOOL", r"(?i:true|false|yes|no)"),
+    (
+        "UUID",
+        r"[A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}",
+    ),
+    ("IPV4", r"(?:\d{1,3}\.){3}\d{1,3}"),
+    ("IPV6", r"[0-9A-Fa-f]*:[0-9A-Fa-f:.]*"),
+    ("IP", r"%{IPV4}|%{IPV6}"),
+    (
+        "HOSTNAME",
+        r"\b[0-9A-Za-z][0-9A-Za-z-]*(?:\.[0-9A-Za-z][0-9A-Za-z-]*)*\b",
+    ),
+    ("PORT", r"\d{1,5}"),
+    ("HOSTPORT", r"(?:%{IP}|%{HOSTNAME}):%{PORT}"),
+    ("PATH", r"(?:/[^\s?#]*)+"),
+    ("URI", r"[A-Za-z][A-Za-z0-9+.-]*://\S+"),
+    ("QUOTEDSTRING", r#""(?:[^"\\]|\\.)*""#),
+    (
+        "LOGLEVEL",
+        r"(?i:trace|debug|info|notice|warn(?:ing)?|err(?:or)?|crit(?:ical)?|fatal|panic|alert|emerg)",
+    ),
+    (
+        "TIMESTAMP_ISO8601",
+        r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}(?::\d{2}(?:\.\d+)?)?(?:Z|[+-]\d{2}:?\d{2})?",
+    ),
+    ("DURATION", r"\d+(?:\.\d+)?(?:ns|us|µs|ms|s|m|h)"),
+    ("EMAILADDRESS", r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+"),
+];
+
+/// Deepest nesting of `%{NAME}` references, which catches patterns referring to themselves.
+const MAX_DEPTH: usize = 16;
+
+/// Start of the names of the groups `%{NAME:field}` references become, which patterns may
+/// not use for groups of their own.
+const GROUP_PREFIX: &str = "__grok_";
+
+lazy_static! {
+    static ref REFERENCE: Regex = Regex::new(r"%\{(\w+)(?::([\w.@-]+))?(?::(\w+))?\}").unwrap();
+    static ref RESERVED_GROUP: Regex = Regex::new(&format!(r"\(\?P?<{}", GROUP_PREFIX)).unwrap();
+}
+
+/// Type a captured string is converted to; the string is kept when it does not convert.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    #[default]
+    String,
+    Int,
+    Float,
+    Bool,
+    /// Any time the consumer accepts in payloads, stored as RFC3339.
+    Timestamp,
+}
+
+impl FromStr for FieldType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(FieldType::String),
+            "int" => Ok(FieldType::Int),
+            "float" => Ok(FieldType::Float),
+            "bool" => Ok(FieldType::Bool),
+            "timestamp" => Ok(FieldType::Timestamp),
+            _ => Err(format!(
+                "unknown type {:?}, expected string, int, float, bool or timestamp",
+                s
+            )),
+        }
+    }
+}
+
+impl FieldType {
+    fn coerce(&self, text: &str, timestamps: &TimestampParser) -> Value {
+        let converted = match self {
+            FieldType::String => None,
+            FieldType::Int => text.parse::<i64>().ok().map(Value::from),
+            FieldType::Float => text
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number),
+            FieldType::Bool => match text.to_ascii_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Some(Value::Bool(true)),
+                "false" | "no" | "off" | "0" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            FieldType::Timestamp => timestamps
+                .parse(&Value::String(text.to_owned()))
+                .map(|time| Value::String(time.to_rfc3339())),
+        };
+        converted.unwrap_or_else(|| Value::String(text.to_owned()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    patterns: HashMap<String, String>,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: String,
+    #[serde(default = "default_field")]
+    field: String,
+    pattern: String,
+    #[serde(default)]
+    types: HashMap<String, FieldType>,
+}
+
+fn default_field() -> String {
+    "message".to_owned()
+}
+
+struct Rule {
+    name: String,
+    field: String,
+    regex: Regex,
+    /// Group name in the regex, field it fills, and its type.
+    captures: Vec<(String, String, FieldType)>,
+    attempts: IntCounter,
+    matches: IntCounter,
+}
+
+/// The rules of a file, applied in order to every log.
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let error = |detail: String| ConfigError(format!("{}: {}", path.display(), detail));
+        let text =
+            std::fs::read_to_string(path).map_err(|e| error(format!("cannot read: {}", e)))?;
+        Self::parse(&text).map_err(error)
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let file: RuleFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        if let Some(name) = file
+            .patterns
+            .iter()
+            .find_map(|(name, pattern)| RESERVED_GROUP.is_match(pattern).then_some(name))
+        {
+            return Err(reserved_group(&format!("pattern {}", name)));
+        }
+        let mut patterns: HashMap<String, String> = BUILTIN_PATTERNS
+            .iter()
+            .map(|(name, pattern)| (name.to_string(), pattern.to_string()))
+            .collect();
+        patterns.extend(file.patterns);
+        let rules = file
+            .rules
+            .into_iter()
+            .map(|rule| {
+                Rule::compile(rule, &patterns)
+                    .map_err(|(name, detail)| format!("rule {}: {}", name, detail))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rules { rules })
+    }
+
+    /// Add the fields captured by matching rules to `data`, leaving fields already there.
+    pub fn apply(&self, data: &mut Map<String, Value>, timestamps: &TimestampParser) {
+        for rule in &self.rules {
+            let Some(Value::String(text)) = data.get(&rule.field) else {
+                continue;
+            };
+            rule.attempts.inc();
+            let Some(captured) = rule.regex.captures(text) else {
+                continue;
+            };
+            rule.matches.inc();
+            let fields: Vec<(String, Value)> = rule
+                .captures
+                .iter()
+                .filter_map(|(group, field, kind)| {
+                    let text = captured.name(group)?.as_str();
+                    Some((field.clone(), kind.coerce(text, timestamps)))
+                })
+                .collect();
+            for (field, value) in fields {
+                insert_field(data, &field, value);
+            }
+        }
+    }
+
+    /// Name, matches and attempts of each rule, for logging match rates.
+    pub fn match_rates(&self) -> Vec<(&str, u64, u64)> {
+        self.rules
+            .iter()
+            .map(|rule| (rule.name.as_str(), rule.matches.get(), rule.attempts.get()))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl Rule {
+    fn compile(
+        config: RuleConfig,
+        patterns: &HashMap<String, String>,
+    ) -> Result<Self, (String, String)> {
+        let name = config.name.clone();
+        let fail = |detail: String| (name.clone(), detail);
+        if RESERVED_GROUP.is_match(&config.pattern) {
+            return Err(fail(reserved_group("its pattern")));
+        }
+        let mut captures = Vec::new();
+        let expanded = expand(&config.pattern, patterns, 0, &mut captures).map_err(fail)?;
+        let regex = Regex::new(&expanded).map_err(|e| fail(e.to_string()))?;
+        // groups written as (?P<field>...) capture too
+        for group in regex.capture_names().flatten() {
+            if !captures.iter().any(|(name, _, _)| name == group) {
+                captures.push((group.to_owned(), group.to_owned(), FieldType::default()));
+            }
+        }
+        for (_, field, kind) in captures.iter_mut() {
+            if let Some(declared) = config.types.get(field.as_str()) {
+                *kind = *declared;
+            }
+        }
+        if let Some((_, field, _)) = captures
+            .iter()
+            .find(|(_, field, _)| field.split('.').any(str::is_empty))
+        {
+            return Err(fail(format!(
+                "captures into {:?}, which has an empty part between dots",
+                field
+            )));
+        }
+        if let Some(field) = config
+            .types
+            .keys()
+            .find(|field| !captures.iter().any(|(_, captured, _)| captured == *field))
+        {
+            return Err(fail(format!(
+                "types names {:?}, which is not captured",
+                field
+            )));
+        }
+        Ok(Rule {
+            attempts: RULE_ATTEMPTS.with_label_values(&[&config.name]),
+            matches: RULE_MATCHES.with_label_values(&[&config.name]),
+            name: config.name,
+            field: config.field,
+            regex,
+            captures,
+        })
+    }
+}
+
+/// Insert `value` at the dotted path `field`, creating the objects it goes through. A field
+/// already there is kept, as is the value when the path goes through something else than an
+/// object.
+fn insert_field(data: &mut Map<String, Value>, field: &str, value: Value) {
+    let (parents, name) = match field.rsplit_once('.') {
+        Some((parents, name)) => (Some(parents), name),
+        None => (None, field),
+    };
+    let mut object = data;
+    for key in parents.into_iter().flat_map(|parents| parents.split('.')) {
+        match object
+            .entry(key)
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            Value::Object(child) => object = child,
+            _ => return,
+        }
+    }
+    object.entry(name).or_insert(value);
+}
+
+fn reserved_group(owner: &str) -> String {
+    format!(
+        "{} names a group starting with {:?}, which is reserved for %{{NAME:field}} captures",
+        owner, GROUP_PREFIX
+    )
+}
+
+/// Replace `%{NAME}` references, turning those naming a field into capture groups.
+fn expand(
+    pattern: &str,
+    patterns: &HashMap<String, String>,
+    depth: usize,
+    captures: &mut Vec<(String, String, FieldType)>,
+) -> Result<String, String> {
+    if depth > MAX_DEPTH {
+        return Err("patterns nested too deep, one may refer to itself".to_owned());
+    }
+    let mut expanded = String::new();
+    let mut last = 0;
+    for reference in REFERENCE.captures_iter(pattern) {
+        let whole = reference.get(0).unwrap();
+        expanded.push_str(&pattern[last..whole.start()]);
+        last = whole.end();
+        let name = &reference[1];
+        let definition = patterns
+            .get(name)
+            .ok_or_else(|| format!("unknown pattern %{{{}}}", name))?;
+        let inner = expand(definition, patterns, depth + 1, captures)?;
+        match reference.get(2) {
+            Some(field) => {
+                let kind = match reference.get(3) {
+                    Some(kind) => kind.as_str().parse()?,
+                    None => FieldType::default(),
+                };
+                // field names may hold dots, which group names cannot
+                let group = format!("{}{}", GROUP_PREFIX, captures.len());
+                expanded.push_str(&format!("(?P<{}>{})", group, inner));
+                captures.push((group, field.as_str().to_owned(), kind));
+            }
+            None => expanded.push_str(&format!("(?:{})", inner)),
+        }
+    }
+    expanded.push_str(&pattern[last..]);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(rules: &str, message: &str) -> Value {
+        let rules = Rules::parse(rules).unwrap();
+        let mut data = Map::new();
+        data.insert("message".to_owned(), message.into());
+        rules.apply(&mut data, &TimestampParser::default());
+        Value::Object(data)
+    }
+
+    #[test]
+    fn user_groups_do_not_collide_with_captures() {
+        let rules = r#"
+            [[rules]]
+            name = "status"
+            pattern = '(?P<capture0>\w+) %{INT:code:int}'
+        "#;
+        assert_eq!(
+            apply(rules, "done 200"),
+            json!({"message": "done 200", "capture0": "done", "code": 200})
+        );
+    }
+
+    #[test]
+    fn rejects_reserved_group_names() {
+        let error = Rules::parse(
+            r#"
+            [[rules]]
+            name = "clash"
+            pattern = '(?P<__grok_0>\w+) %{INT:code}'
+        "#,
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("rule clash"), "{}", error);
+        assert!(error.contains("reserved"), "{}", error);
+        let error = Rules::parse(
+            r#"
+            patterns = { CODE = '(?<__grok_code>\d+)' }
+            [[rules]]
+            name = "code"
+            pattern = '%{CODE}'
+        "#,
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("pattern CODE"), "{}", error);
+    }
+
+    #[test]
+    fn expands_references_into_typed_captures() {
+        let rules = r#"
+            [patterns]
+            ENDPOINT = '%{WORD:endpoint.method} %{PATH:endpoint.path}'
+
+            [[rules]]
+            name = "request"
+            pattern = '^%{TIMESTAMP_ISO8601:at:timestamp} %{LOGLEVEL} %{ENDPOINT} took %{NUMBER:ms:float}ms cached=%{BOOL:cached:bool} from %{IP:client}( user (?P<user>\w+))?'
+            types = { "user" = "string" }
+        "#;
+        assert_eq!(
+            apply(
+                rules,
+                "2024-05-01T10:00:00Z INFO GET /api/logs took 12.5ms cached=yes from 10.0.0.1 user ann"
+            ),
+            json!({
+                "message": "2024-05-01T10:00:00Z INFO GET /api/logs took 12.5ms cached=yes from 10.0.0.1 user ann",
+                "at": "2024-05-01T10:00:00+00:00",
+                "endpoint": {"method": "GET", "path": "/api/logs"},
+                "ms": 12.5,
+                "cached": true,
+                "client": "10.0.0.1",
+                "user": "ann",
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_fields_and_text_which_do_not_convert() {
+        let rules = r#"
+            [[rules]]
+            name = "count"
+            pattern = 'count=%{NOTSPACE:count:int} level=%{WORD:level}'
+        "#;
+        assert_eq!(
+            apply(rules, "count=many level=debug"),
+            json!({"message": "count=many level=debug", "count": "many", "level": "debug"})
+        );
+        let rules = Rules::parse(rules).unwrap();
+        let mut data = Map::new();
+        data.insert("message".to_owned(), "count=1 level=debug".into());
+        data.insert("level".to_owned(), "ERROR".into());
+        rules.apply(&mut data, &TimestampParser::default());
+        assert_eq!(data["count"], 1);
+        assert_eq!(data["level"], "ERROR");
+
+        let rules = Rules::parse(
+            "[[rules]]\nname = \"http\"\npattern = '%{WORD:http.method} %{WORD:user.name}'",
+        )
+        .unwrap();
+        let mut data = json!({"message": "GET ann", "http": {"path": "/"}, "user": "bob"})
+            .as_object()
+            .unwrap()
+            .clone();
+        rules.apply(&mut data, &TimestampParser::default());
+        assert_eq!(
+            Value::Object(data),
+            json!({"message": "GET ann", "http": {"path": "/", "method": "GET"}, "user": "bob"})
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for (pattern, expected) in [
+            ("%{NOPE:x}", "unknown pattern %{NOPE}"),
+            ("%{INT:x:date}", "unknown type"),
+            ("%{LOOP}", "nested too deep"),
+            ("%{INT:x} (", "unclosed group"),
+            ("%{INT:x..y}", "empty part"),
+        ] {
+            let error = Rules::parse(&format!(
+                "patterns = {{ LOOP = '%{{LOOP}}' }}\n[[rules]]\nname = \"r\"\npattern = '{}'",
+                pattern
+            ))
+            .err()
+            .unwrap();
+            assert!(error.contains(expected), "{}: {}", pattern, error);
+        }
+        let error =
+            Rules::parse("[[rules]]\nname = \"r\"\npattern = '%{INT:x}'\ntypes = { y = \"int\" }")
+                .err()
+                .unwrap();
+        assert!(error.contains("not captured"), "{}", error);
+    }
+}
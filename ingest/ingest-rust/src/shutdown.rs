@@ -0,0 +1,22 @@
+//! Stop signals, which the binaries answer by draining what they hold before exiting.
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// A token cancelled on the first SIGTERM or SIGINT.
+pub fn on_signal() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    let mut terminate = signal(SignalKind::terminate()).expect("cannot listen to SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("cannot listen to SIGINT");
+    tokio::spawn(async move {
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        };
+        info!("received {}, shutting down", name);
+        cancel.cancel();
+    });
+    token
+}
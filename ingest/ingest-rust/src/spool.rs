@@ -0,0 +1,263 @@
+//! On-disk queue of the batches the producer could not publish, and the publisher
+//! going through it while the broker is unreachable.
+
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::time::{timeout, Instant};
+use tracing::{info, warn};
+
+use crate::{
+    config::AmqpConfig,
+    metrics::{BATCHES_PUBLISHED, SPOOLED_BATCHES, SPOOL_BYTES, SPOOL_DROPPED_BATCHES},
+    publisher::{PublishError, Publisher},
+};
+
+/// Longest wait for the broker to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Batches kept as one file each, named after their sequence number so they replay in order,
+/// including those left by a previous run.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Sequence number and size of each batch, oldest first.
+    batches: VecDeque<(u64, u64)>,
+    bytes: u64,
+    next: u64,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut batches = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("batch") => {}
+                // a batch being written when the producer stopped
+                Some("tmp") => {
+                    std::fs::remove_file(&path)?;
+                    continue;
+                }
+                _ => continue,
+            }
+            if let Some(sequence) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                batches.push((sequence, std::fs::metadata(&path)?.len()));
+            }
+        }
+        batches.sort_unstable();
+        let spool = Spool {
+            dir: dir.to_owned(),
+            max_bytes,
+            bytes: batches.iter().map(|(_, size)| size).sum(),
+            next: batches.last().map_or(0, |(sequence, _)| sequence + 1),
+            batches: batches.into(),
+        };
+        if !spool.is_empty() {
+            info!(
+                "{} batches left in the spool, publishing them first",
+                spool.len()
+            );
+        }
+        spool.update_metrics();
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    /// Add a batch after the others, dropping the oldest ones past the size limit.
+    pub fn push(&mut self, payload: &[u8]) -> io::Result<()> {
+        let path = self.path(self.next);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, payload)?;
+        std::fs::rename(&temporary, &path)?;
+        self.batches.push_back((self.next, payload.len() as u64));
+        self.bytes += payload.len() as u64;
+        self.next += 1;
+        let mut dropped = 0;
+        while self.bytes > self.max_bytes {
+            self.pop_front()?;
+            dropped += 1;
+        }
+        if dropped > 0 {
+            SPOOL_DROPPED_BATCHES.inc_by(dropped);
+            warn!(
+                "spool is over {} bytes, dropped its {} oldest batches ({} so far)",
+                self.max_bytes,
+                dropped,
+                SPOOL_DROPPED_BATCHES.get()
+            );
+        }
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// The oldest batch.
+    pub fn front(&self) -> io::Result<Option<Vec<u8>>> {
+        match self.batches.front() {
+            Some((sequence, _)) => std::fs::read(self.path(*sequence)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Forget the oldest batch, once published.
+    pub fn pop_front(&mut self) -> io::Result<()> {
+        if let Some((sequence, size)) = self.batches.pop_front() {
+            self.bytes -= size;
+            self.update_metrics();
+            std::fs::remove_file(self.path(sequence))?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, sequence: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.batch", sequence))
+    }
+
+    fn update_metrics(&self) {
+        SPOOLED_BATCHES.set(self.batches.len() as i64);
+        SPOOL_BYTES.set(self.bytes as i64);
+    }
+}
+
+/// Publishes batches when the broker is reachable, spools them when it is not, and
+/// reconnects with exponential backoff, replaying the spool before any new batch.
+pub struct SpooledPublisher {
+    amqp: AmqpConfig,
+    source: String,
+    publisher: Option<Publisher>,
+    spool: Spool,
+    initial_delay: Duration,
+    max_delay: Duration,
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+impl SpooledPublisher {
+    /// Connecting waits for the first batch, so a missing broker does not stop the producer.
+    pub fn new(
+        amqp: AmqpConfig,
+        source: String,
+        spool: Spool,
+        initial_delay: Duration,
+        max_delay: Duration,
+    ) -> Self {
+        SpooledPublisher {
+            amqp,
+            source,
+            publisher: None,
+            spool,
+            initial_delay,
+            max_delay,
+            delay: initial_delay,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Batches waiting in the spool.
+    pub fn spooled(&self) -> usize {
+        self.spool.len()
+    }
+
+    /// Publish a batch after those already spooled; it is spooled when the broker cannot take it.
+    /// Only failing to write the spool is an error.
+    pub async fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        if self.spool.is_empty() && self.connected().await {
+            let publisher = self.publisher.as_mut().unwrap();
+            match publisher.publish_payload(payload.clone()).await {
+                Ok(()) => {
+                    BATCHES_PUBLISHED.inc();
+                    return Ok(());
+                }
+                Err(err) => self.disconnect(err),
+            }
+        }
+        self.spool.push(&payload)?;
+        self.flush().await
+    }
+
+    /// Publish spooled batches, oldest first, for as long as the broker takes them.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        while !self.spool.is_empty() && self.connected().await {
+            let payload = self.spool.front()?.unwrap();
+            let publisher = self.publisher.as_mut().unwrap();
+            match publisher.publish_payload(payload).await {
+                Ok(()) => {
+                    BATCHES_PUBLISHED.inc();
+                    self.spool.pop_front()?;
+                    if self.spool.is_empty() {
+                        info!("spool replayed");
+                    }
+                }
+                Err(err) => self.disconnect(err),
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn close(self) {
+        if let Some(publisher) = self.publisher {
+            publisher.close().await;
+        }
+    }
+
+    /// Whether there is an open connection, opening one if the backoff allows it.
+    async fn connected(&mut self) -> bool {
+        match &self.publisher {
+            Some(publisher) if publisher.is_open() => return true,
+            Some(_) => {
+                warn!("lost the connection to the broker");
+                self.publisher = None;
+            }
+            None => {}
+        }
+        if Instant::now() < self.next_attempt {
+            return false;
+        }
+        let error = match timeout(
+            CONNECT_TIMEOUT,
+            Publisher::connect(&self.amqp, &self.source),
+        )
+        .await
+        {
+            Ok(Ok(publisher)) => {
+                info!("connected to the broker");
+                self.publisher = Some(publisher);
+                self.delay = self.initial_delay;
+                return true;
+            }
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => format!("no answer within {:?}", CONNECT_TIMEOUT),
+        };
+        warn!(
+            "cannot connect to the broker, spooling batches and retrying in {:?}: {}",
+            self.delay, error
+        );
+        self.next_attempt = Instant::now() + self.delay;
+        self.delay = min(self.delay * 2, self.max_delay);
+        false
+    }
+
+    fn disconnect(&mut self, err: PublishError) {
+        warn!("cannot publish, spooling batches: {}", err);
+        self.publisher = None;
+        self.next_attempt = Instant::now() + self.delay;
+        self.delay = min(self.delay * 2, self.max_delay);
+    }
+}
@@ -0,0 +1,391 @@
+use std::{io, mem, net::SocketAddr, time::Duration};
+
+use logdog_rust::{
+    config::{NonJsonMode, SyslogConfig},
+    formats::Format,
+    metrics::{self, SYSLOG_CONNECTIONS, SYSLOG_MESSAGES},
+    record, shutdown,
+    spool::{Spool, SpooledPublisher},
+};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc::{self, Sender},
+    time::{sleep, sleep_until, timeout, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+/// How often the spool is replayed while no message arrives.
+const SPOOL_FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Most digits of the length prefixing an octet counted frame.
+const MAX_LENGTH_DIGITS: usize = 10;
+
+/// The record of a received message, tagged with the address of its sender.
+fn to_record(message: &[u8], peer: SocketAddr) -> Option<Value> {
+    let text = String::from_utf8_lossy(message);
+    let mut record = record::to_record(
+        text.trim_end_matches(['\r', '\n', '\0']),
+        Format::Syslog,
+        NonJsonMode::Wrap,
+    )?;
+    if let Value::Object(data) = &mut record {
+        data.entry("peer")
+            .or_insert_with(|| peer.ip().to_string().into());
+    }
+    Some(record)
+}
+
+/// Receive one message per datagram, until shutdown.
+async fn receive_udp(
+    socket: UdpSocket,
+    max_message_bytes: usize,
+    tx: Sender<Value>,
+    shutdown: CancellationToken,
+) {
+    let counter = SYSLOG_MESSAGES.with_label_values(&["udp"]);
+    // longer datagrams are truncated by the socket
+    let mut buffer = vec![0; max_message_bytes];
+    loop {
+        let (size, peer) = tokio::select! {
+            received = socket.recv_from(&mut buffer) => match received {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("cannot receive datagram: {}", err);
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => return,
+        };
+        counter.inc();
+        if let Some(record) = to_record(&buffer[..size], peer) {
+            if tx.send(record).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Accept connections, each read by a task of its own, until shutdown.
+async fn accept_tcp(
+    listener: TcpListener,
+    max_message_bytes: usize,
+    tx: Sender<Value>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("cannot accept connection: {}", err);
+                    sleep(SPOOL_FLUSH_INTERVAL).await;
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => return,
+        };
+        let tx = tx.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            SYSLOG_CONNECTIONS.inc();
+            debug!("syslog connection from {}", peer);
+            tokio::select! {
+                result = receive_tcp(stream, peer, max_message_bytes, tx) => {
+                    if let Err(err) = result {
+                        warn!("closing syslog connection from {}: {}", peer, err);
+                    }
+                }
+                _ = shutdown.cancelled() => {}
+            }
+            SYSLOG_CONNECTIONS.dec();
+        });
+    }
+}
+
+/// Read the messages of a connection until the sender closes it.
+async fn receive_tcp(
+    stream: TcpStream,
+    peer: SocketAddr,
+    max_message_bytes: usize,
+    tx: Sender<Value>,
+) -> io::Result<()> {
+    let counter = SYSLOG_MESSAGES.with_label_values(&["tcp"]);
+    let mut reader = BufReader::new(stream);
+    let mut message = Vec::new();
+    while read_frame(&mut reader, max_message_bytes, &mut message).await? {
+        counter.inc();
+        if let Some(record) = to_record(&message, peer) {
+            if tx.send(record).await.is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the next message into `message`, as RFC 6587 frames it: octet counted when it starts
+/// with its length, newline terminated otherwise. Longer messages are truncated to `max_bytes`.
+/// Returns false once the connection is closed.
+async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_bytes: usize,
+    message: &mut Vec<u8>,
+) -> io::Result<bool> {
+    message.clear();
+    let first = match reader.fill_buf().await?.first() {
+        Some(first) => *first,
+        None => return Ok(false),
+    };
+    if first.is_ascii_digit() {
+        let mut digits = Vec::new();
+        (&mut *reader)
+            .take(MAX_LENGTH_DIGITS as u64 + 1)
+            .read_until(b' ', &mut digits)
+            .await?;
+        let length: u64 = std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|digits| digits.strip_suffix(' '))
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid octet count"))?;
+        let kept = length.min(max_bytes as u64);
+        (&mut *reader).take(kept).read_to_end(message).await?;
+        if (message.len() as u64) < kept {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        tokio::io::copy(
+            &mut (&mut *reader).take(length - kept),
+            &mut tokio::io::sink(),
+        )
+        .await?;
+    } else {
+        (&mut *reader)
+            .take(max_bytes as u64)
+            .read_until(b'\n', message)
+            .await?;
+        if message.last() != Some(&b'\n') && message.len() == max_bytes {
+            // skip the rest of a message too long
+            let mut rest = Vec::new();
+            loop {
+                rest.clear();
+                let read = (&mut *reader)
+                    .take(max_bytes as u64)
+                    .read_until(b'\n', &mut rest)
+                    .await?;
+                if read == 0 || rest.last() == Some(&b'\n') {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Publish records as one batch, or spool them.
+async fn publish(publisher: &mut SpooledPublisher, batch: Vec<Value>) -> io::Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let payload = serde_json::to_vec(&batch).expect("JSON values always serialize");
+    publisher.send(payload).await
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main() {
+    // construct a subscriber that prints formatted traces to stdout
+    // global subscriber with log level according to RUST_LOG
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(EnvFilter::from_default_env())
+        .try_init()
+        .ok();
+
+    let config = SyslogConfig::load().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(2);
+    });
+    let settings = &config.syslog;
+    let shutdown = shutdown::on_signal();
+    if let Some(listen) = settings.metrics_address() {
+        tokio::spawn(metrics::serve(listen));
+    }
+
+    let spool =
+        Spool::open(&settings.spool_dir, settings.spool_max_bytes).unwrap_or_else(|error| {
+            eprintln!(
+                "cannot open spool {}: {}",
+                settings.spool_dir.display(),
+                error
+            );
+            std::process::exit(2);
+        });
+    let mut publisher = SpooledPublisher::new(
+        config.amqp.clone(),
+        settings.source(),
+        spool,
+        settings.retry_initial_delay(),
+        settings.retry_max_delay(),
+    );
+    let batch_lines = settings.batch_lines;
+    // bounded, so listeners wait for publishing instead of piling up messages
+    let (tx, mut rx) = mpsc::channel(batch_lines * 16);
+    let cannot_listen = |address: SocketAddr, error: io::Error| -> ! {
+        eprintln!("cannot listen on {}: {}", address, error);
+        std::process::exit(2);
+    };
+    if let Some(address) = settings.udp_address() {
+        let socket = UdpSocket::bind(address)
+            .await
+            .unwrap_or_else(|error| cannot_listen(address, error));
+        info!("receiving syslog datagrams on {}", address);
+        tokio::spawn(receive_udp(
+            socket,
+            settings.max_message_bytes,
+            tx.clone(),
+            shutdown.clone(),
+        ));
+    }
+    if let Some(address) = settings.tcp_address() {
+        let listener = TcpListener::bind(address)
+            .await
+            .unwrap_or_else(|error| cannot_listen(address, error));
+        info!("accepting syslog connections on {}", address);
+        tokio::spawn(accept_tcp(
+            listener,
+            settings.max_message_bytes,
+            tx.clone(),
+            shutdown.clone(),
+        ));
+    }
+    drop(tx);
+
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now();
+    let mut failed = false;
+    loop {
+        let wake = match batch.is_empty() {
+            true => Instant::now() + SPOOL_FLUSH_INTERVAL,
+            false => deadline,
+        };
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some(record) => {
+                    if batch.is_empty() {
+                        deadline = Instant::now() + settings.batch_linger();
+                    }
+                    batch.push(record);
+                    if batch.len() < batch_lines {
+                        continue;
+                    }
+                }
+                None => break,
+            },
+            _ = sleep_until(wake) => {
+                if batch.is_empty() {
+                    if let Err(err) = publisher.flush().await {
+                        error!("cannot read spool: {}", err);
+                    }
+                    continue;
+                }
+            }
+            _ = shutdown.cancelled() => break,
+        }
+        if let Err(err) = publish(&mut publisher, mem::take(&mut batch)).await {
+            error!("cannot spool batch: {}", err);
+            failed = true;
+            break;
+        }
+    }
+
+    // publish what was received but not sent yet, the spool first
+    rx.close();
+    let drain = async {
+        publisher.flush().await?;
+        while let Ok(record) = rx.try_recv() {
+            batch.push(record);
+            if batch.len() >= batch_lines {
+                publish(&mut publisher, mem::take(&mut batch)).await?;
+            }
+        }
+        publish(&mut publisher, mem::take(&mut batch)).await
+    };
+    let drained = match timeout(settings.shutdown_timeout(), drain).await {
+        Ok(Ok(())) => !failed,
+        Ok(Err(err)) => {
+            error!("cannot publish pending messages: {}", err);
+            false
+        }
+        Err(_) => {
+            error!(
+                "gave up publishing pending messages after {:?}",
+                settings.shutdown_timeout()
+            );
+            false
+        }
+    };
+    if publisher.spooled() > 0 {
+        info!(
+            "{} batches left in the spool, published on the next start",
+            publisher.spooled()
+        );
+    }
+    publisher.close().await;
+    if !drained {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every frame of `stream`, read with messages truncated to `max_bytes`.
+    async fn frames(stream: &[u8], max_bytes: usize) -> io::Result<Vec<String>> {
+        let mut reader = BufReader::new(stream);
+        let mut message = Vec::new();
+        let mut frames = Vec::new();
+        while read_frame(&mut reader, max_bytes, &mut message).await? {
+            frames.push(String::from_utf8_lossy(&message).into_owned());
+        }
+        Ok(frames)
+    }
+
+    #[tokio::test]
+    async fn reads_octet_counted_and_newline_frames() {
+        let stream = b"10 <13>first\n11 <13>second\n<13>third\n<13>fourth";
+        assert_eq!(
+            frames(stream, 1024).await.unwrap(),
+            ["<13>first\n", "<13>second\n", "<13>third\n", "<13>fourth"]
+        );
+        // a count covers newlines within the message
+        assert_eq!(
+            frames(b"9 <13>a\nb\nc", 1024).await.unwrap(),
+            ["<13>a\nb\nc"]
+        );
+    }
+
+    #[tokio::test]
+    async fn truncates_long_messages_and_reads_on() {
+        assert_eq!(
+            frames(b"12 <13>abcdefgh5 <13>x", 6).await.unwrap(),
+            ["<13>ab", "<13>x"]
+        );
+        assert_eq!(
+            frames(b"<13>abcdefghijklmnop\n<13>y\n", 6).await.unwrap(),
+            ["<13>ab", "<13>y\n"]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_broken_frames() {
+        let invalid = frames(b"12345678901 <13>x", 1024).await.unwrap_err();
+        assert_eq!(invalid.kind(), io::ErrorKind::InvalidData);
+        let invalid = frames(b"12x <13>x", 1024).await.unwrap_err();
+        assert_eq!(invalid.kind(), io::ErrorKind::InvalidData);
+        let truncated = frames(b"20 <13>short", 1024).await.unwrap_err();
+        assert_eq!(truncated.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
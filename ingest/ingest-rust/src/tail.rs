@@ -0,0 +1,277 @@
+//! Tailing of log files, following them through rotation, and the checkpoint keeping
+//! how far each one was published so a restarted producer resumes where it stopped.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io,
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Most bytes read from one file per poll, so a large backlog does not fill memory.
+const MAX_READ: u64 = 1 << 20;
+
+/// Files are told apart by device and inode, which survive renames.
+type FileId = (u64, u64);
+
+/// Where a file was read up to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Position {
+    pub path: PathBuf,
+    pub dev: u64,
+    pub inode: u64,
+    pub offset: u64,
+}
+
+impl Position {
+    fn id(&self) -> FileId {
+        (self.dev, self.inode)
+    }
+}
+
+/// A line to publish, with the position following it when it comes from a file.
+#[derive(Debug)]
+pub struct Line {
+    pub text: String,
+    pub position: Option<Position>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CheckpointFile {
+    files: Vec<Position>,
+}
+
+/// Positions of the last published line of each file, saved as JSON.
+pub struct Checkpoint {
+    path: PathBuf,
+    positions: HashMap<FileId, Position>,
+    dirty: bool,
+    saved_at: Instant,
+}
+
+impl Checkpoint {
+    /// Read the checkpoint at `path`, empty if it does not exist yet.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let saved: CheckpointFile = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => CheckpointFile::default(),
+            Err(error) => return Err(error),
+        };
+        Ok(Checkpoint {
+            path: path.to_owned(),
+            positions: saved
+                .files
+                .into_iter()
+                .map(|position| (position.id(), position))
+                .collect(),
+            dirty: false,
+            saved_at: Instant::now(),
+        })
+    }
+
+    /// Record that everything up to `position` was published.
+    pub fn advance(&mut self, position: &Position) {
+        self.positions.insert(position.id(), position.clone());
+        self.dirty = true;
+    }
+
+    /// Save if something was published since the last save, and that save is older than `every`.
+    pub fn save_every(&mut self, every: Duration) -> io::Result<()> {
+        if self.dirty && self.saved_at.elapsed() >= every {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Write the positions of the files still there, replacing the checkpoint atomically.
+    pub fn save(&mut self) -> io::Result<()> {
+        // rotated files gone from their path were read to their end, they are not needed anymore
+        self.positions.retain(|id, position| {
+            std::fs::metadata(&position.path)
+                .map(|metadata| (metadata.dev(), metadata.ino()) == *id)
+                .unwrap_or(false)
+        });
+        let saved = CheckpointFile {
+            files: self.positions.values().cloned().collect(),
+        };
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, serde_json::to_vec(&saved)?)?;
+        std::fs::rename(&temporary, &self.path)?;
+        self.dirty = false;
+        self.saved_at = Instant::now();
+        Ok(())
+    }
+}
+
+struct TailedFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes read so far, including those of `partial`.
+    offset: u64,
+    /// Start of a line whose end was not written yet.
+    partial: Vec<u8>,
+}
+
+impl TailedFile {
+    /// Read up to `limit` new bytes, returning the complete lines.
+    fn read(&mut self, id: FileId, limit: u64) -> io::Result<Vec<Line>> {
+        let length = self.file.metadata()?.len();
+        if length < self.offset {
+            warn!("{} was truncated, reading it again", self.path.display());
+            self.offset = 0;
+            self.partial.clear();
+        }
+        let mut buffer = vec![0; (length - self.offset).min(limit) as usize];
+        let read = self.file.read_at(&mut buffer, self.offset)?;
+        buffer.truncate(read);
+        let start = self.offset - self.partial.len() as u64;
+        self.offset += read as u64;
+        let mut bytes = std::mem::take(&mut self.partial);
+        bytes.extend_from_slice(&buffer);
+
+        let mut lines = Vec::new();
+        let mut line_start = 0;
+        for (index, _) in bytes.iter().enumerate().filter(|(_, &byte)| byte == b'\n') {
+            lines.push(self.line(id, &bytes[line_start..index], start + index as u64 + 1));
+            line_start = index + 1;
+        }
+        self.partial = bytes[line_start..].to_vec();
+        Ok(lines)
+    }
+
+    /// Read what is left of a file that is not followed anymore, its last line included.
+    fn read_to_end(&mut self, id: FileId) -> io::Result<Vec<Line>> {
+        let mut lines = Vec::new();
+        loop {
+            let offset = self.offset;
+            lines.extend(self.read(id, MAX_READ)?);
+            if self.offset == offset {
+                break;
+            }
+        }
+        if !self.partial.is_empty() {
+            let partial = std::mem::take(&mut self.partial);
+            lines.push(self.line(id, &partial, self.offset));
+        }
+        Ok(lines)
+    }
+
+    fn line(&self, (dev, inode): FileId, bytes: &[u8], offset: u64) -> Line {
+        let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        Line {
+            text: String::from_utf8_lossy(bytes).into_owned(),
+            position: Some(Position {
+                path: self.path.clone(),
+                dev,
+                inode,
+                offset,
+            }),
+        }
+    }
+}
+
+/// Follows the files matching a set of globs.
+///
+/// A file renamed away from the globs, as most rotations do, is read to its end before being
+/// dropped, and one truncated in place is read again from its start.
+pub struct Tailer {
+    patterns: Vec<String>,
+    /// Checkpointed offsets of files not opened yet.
+    start_offsets: HashMap<FileId, u64>,
+    files: HashMap<FileId, TailedFile>,
+}
+
+impl Tailer {
+    pub fn new(patterns: Vec<String>, checkpoint: &Checkpoint) -> Self {
+        Tailer {
+            patterns,
+            start_offsets: checkpoint
+                .positions
+                .iter()
+                .map(|(id, position)| (*id, position.offset))
+                .collect(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Lines written since the last poll, in the order of each file.
+    pub fn poll(&mut self) -> Vec<Line> {
+        let mut seen = HashSet::new();
+        for path in self.matching_paths() {
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let id = (metadata.dev(), metadata.ino());
+            seen.insert(id);
+            if let Some(tailed) = self.files.get_mut(&id) {
+                tailed.path = path;
+                continue;
+            }
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(error) => {
+                    warn!("cannot open {}: {}", path.display(), error);
+                    continue;
+                }
+            };
+            let offset = self
+                .start_offsets
+                .remove(&id)
+                .filter(|offset| *offset <= metadata.len())
+                .unwrap_or(0);
+            info!("tailing {} from offset {}", path.display(), offset);
+            self.files.insert(
+                id,
+                TailedFile {
+                    path,
+                    file,
+                    offset,
+                    partial: Vec::new(),
+                },
+            );
+        }
+
+        let mut lines = Vec::new();
+        // the rest of rotated files comes before the lines of the files replacing them
+        let gone: Vec<FileId> = self
+            .files
+            .keys()
+            .filter(|id| !seen.contains(id))
+            .copied()
+            .collect();
+        for id in gone {
+            let mut tailed = self.files.remove(&id).unwrap();
+            match tailed.read_to_end(id) {
+                Ok(rest) => lines.extend(rest),
+                Err(error) => warn!("cannot read {}: {}", tailed.path.display(), error),
+            }
+            info!("stopped tailing {}", tailed.path.display());
+        }
+        for (id, tailed) in self.files.iter_mut() {
+            match tailed.read(*id, MAX_READ) {
+                Ok(new) => lines.extend(new),
+                Err(error) => warn!("cannot read {}: {}", tailed.path.display(), error),
+            }
+        }
+        lines
+    }
+
+    fn matching_paths(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for pattern in &self.patterns {
+            match glob::glob(pattern) {
+                Ok(matches) => paths.extend(matches.flatten().filter(|path| path.is_file())),
+                Err(error) => warn!("invalid pattern {:?}: {}", pattern, error),
+            }
+        }
+        paths
+    }
+}
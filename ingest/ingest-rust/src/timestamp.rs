@@ -0,0 +1,235 @@
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+
+/// Fields looked up, in order, unless `consumer.time_fields` is set.
+pub const DEFAULT_FIELDS: [&str; 4] = ["time", "ts", "@timestamp", "timestamp"];
+
+/// Finds and parses the event time carried by a log payload.
+///
+/// Accepted values are RFC3339 strings, epoch numbers (seconds, milliseconds,
+/// microseconds or nanoseconds, guessed from magnitude, either as JSON numbers
+/// or numeric strings) and any of the configured strftime formats. Epochs outside
+/// 2000 to 2100 are rather durations or counters, and are not taken as times.
+#[derive(Debug, Clone)]
+pub struct TimestampParser {
+    fields: Vec<String>,
+    formats: Vec<String>,
+}
+
+impl Default for TimestampParser {
+    fn default() -> Self {
+        Self {
+            fields: DEFAULT_FIELDS.iter().map(|f| f.to_string()).collect(),
+            formats: Vec::new(),
+        }
+    }
+}
+
+impl TimestampParser {
+    pub fn new(fields: Vec<String>, formats: Vec<String>) -> Self {
+        Self { fields, formats }
+    }
+
+    /// Remove the first parseable timestamp field from `data` and return its value.
+    ///
+    /// Fields that are present but cannot be parsed are left untouched so that
+    /// the original value remains searchable.
+    pub fn extract(
+        &self,
+        data: &mut serde_json::Map<String, serde_json::Value>,
+    ) -> Option<DateTime<Utc>> {
+        for field in &self.fields {
+            let parsed = match data.get(field) {
+                Some(value) => self.parse(value),
+                None => continue,
+            };
+            if parsed.is_some() {
+                data.remove(field);
+                return parsed;
+            }
+        }
+        None
+    }
+
+    pub fn parse(&self, value: &serde_json::Value) -> Option<DateTime<Utc>> {
+        match value {
+            serde_json::Value::Number(number) => match number.as_i64() {
+                Some(epoch) => from_epoch_integer(epoch),
+                // integers past i64 are beyond any time, others have a fraction
+                None if number.is_u64() => None,
+                None => from_epoch(number.as_f64()?),
+            },
+            serde_json::Value::String(text) => self.parse_str(text.trim()),
+            _ => None,
+        }
+    }
+
+    fn parse_str(&self, text: &str) -> Option<DateTime<Utc>> {
+        if let Ok(epoch) = text.parse::<i64>() {
+            return from_epoch_integer(epoch);
+        }
+        if let Ok(epoch) = text.parse::<f64>() {
+            return from_epoch(epoch);
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+            return Some(time.with_timezone(&Utc));
+        }
+        for format in &self.formats {
+            if let Ok(time) = DateTime::parse_from_str(text, format) {
+                return Some(time.with_timezone(&Utc));
+            }
+            if let Ok(time) = NaiveDateTime::parse_from_str(text, format) {
+                return Some(Utc.from_utc_datetime(&time));
+            }
+        }
+        None
+    }
+}
+
+/// Epoch seconds of 2000-01-01 and 2100-01-01, the range numeric times are accepted in.
+const PLAUSIBLE_EPOCHS: std::ops::Range<i64> = 946_684_800..4_102_444_800;
+
+/// Nanoseconds in the unit of an epoch, guessed from its order of magnitude: seconds,
+/// milliseconds, microseconds or nanoseconds.
+fn epoch_unit(epoch: f64) -> i64 {
+    if epoch < 1e11 {
+        1_000_000_000
+    } else if epoch < 1e14 {
+        1_000_000
+    } else if epoch < 1e17 {
+        1_000
+    } else {
+        1
+    }
+}
+
+/// Interpret an integer epoch, kept exact where nanoseconds as `f64` would be off by up to 128.
+fn from_epoch_integer(epoch: i64) -> Option<DateTime<Utc>> {
+    if epoch < 0 {
+        return None;
+    }
+    plausible(Utc.timestamp_nanos(epoch.checked_mul(epoch_unit(epoch as f64))?))
+}
+
+/// Interpret a fractional epoch, whose whole units are converted exactly and fraction rounded.
+fn from_epoch(epoch: f64) -> Option<DateTime<Utc>> {
+    if !epoch.is_finite() || epoch < 0.0 || epoch >= i64::MAX as f64 {
+        return None;
+    }
+    let unit = epoch_unit(epoch);
+    let nanos = (epoch.trunc() as i64)
+        .checked_mul(unit)?
+        .checked_add((epoch.fract() * unit as f64).round() as i64)?;
+    plausible(Utc.timestamp_nanos(nanos))
+}
+
+fn plausible(time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    PLAUSIBLE_EPOCHS.contains(&time.timestamp()).then_some(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map, Value};
+
+    use super::*;
+
+    #[test]
+    fn integer_epochs_keep_every_nanosecond() {
+        let parser = TimestampParser::default();
+        let nanos = 1_700_000_000_123_456_789;
+        let expected = Utc.timestamp_nanos(nanos);
+        assert_eq!(parser.parse(&json!(nanos)), Some(expected));
+        assert_eq!(parser.parse(&json!(nanos.to_string())), Some(expected));
+        assert_eq!(
+            parser.parse(&json!(1_700_000_000_123_456_i64)),
+            Some(Utc.timestamp_nanos(1_700_000_000_123_456_000))
+        );
+        assert_eq!(
+            parser.parse(&json!(1_700_000_000.25)),
+            Some(Utc.timestamp_nanos(1_700_000_000_250_000_000))
+        );
+        assert_eq!(parser.parse(&json!(u64::MAX)), None);
+        assert_eq!(parser.parse(&json!(-1)), None);
+    }
+
+    #[test]
+    fn implausible_epochs_are_not_times() {
+        let parser = TimestampParser::default();
+        for value in [
+            json!(250),
+            json!(0.5),
+            json!("250"),
+            json!(5_000_000_000_i64),
+            json!(i64::MAX),
+        ] {
+            assert_eq!(parser.parse(&value), None, "{}", value);
+        }
+        let mut data = json!({"time": 250}).as_object().unwrap().clone();
+        assert_eq!(parser.extract(&mut data), None);
+        assert_eq!(Value::Object(data), json!({"time": 250}));
+    }
+
+    #[test]
+    fn epoch_units_are_guessed_from_magnitude() {
+        let parser = TimestampParser::default();
+        let expected = Some(Utc.timestamp_millis_opt(1_700_000_000_000).unwrap());
+        for epoch in [
+            json!(1_700_000_000),
+            json!(1_700_000_000_000_i64),
+            json!(1_700_000_000_000_000_i64),
+            json!(1_700_000_000_000_000_000_i64),
+            json!("1700000000"),
+            json!(1_700_000_000.0),
+        ] {
+            assert_eq!(parser.parse(&epoch), expected, "{}", epoch);
+        }
+        assert_eq!(
+            parser.parse(&json!(1_700_000_000.5)),
+            Some(Utc.timestamp_millis_opt(1_700_000_000_500).unwrap())
+        );
+        assert_eq!(
+            parser.parse(&json!("1700000000.25")),
+            Some(Utc.timestamp_millis_opt(1_700_000_000_250).unwrap())
+        );
+    }
+
+    #[test]
+    fn parses_rfc3339_and_configured_formats() {
+        let parser = TimestampParser::new(
+            vec!["ts".to_owned()],
+            vec![
+                "%d/%b/%Y:%H:%M:%S %z".to_owned(),
+                "%Y-%m-%d %H:%M:%S".to_owned(),
+            ],
+        );
+        let expected = Some(Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap());
+        for text in [
+            "2024-05-01T10:00:00Z",
+            "2024-05-01T12:00:00+02:00",
+            " 01/May/2024:12:00:00 +0200 ",
+            "2024-05-01 10:00:00",
+        ] {
+            assert_eq!(parser.parse(&json!(text)), expected, "{}", text);
+        }
+        for value in [json!("yesterday"), json!(true), json!(null), json!(-5.5)] {
+            assert_eq!(parser.parse(&value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn extracts_the_first_parseable_field() {
+        let parser = TimestampParser::default();
+        let mut data = json!({"time": "soon", "ts": 1_700_000_000, "timestamp": 1})
+            .as_object()
+            .unwrap()
+            .clone();
+        assert_eq!(
+            parser.extract(&mut data),
+            Some(Utc.timestamp_opt(1_700_000_000, 0).unwrap())
+        );
+        // unparsed fields stay searchable, the extracted one is stored as the time column
+        assert_eq!(Value::Object(data), json!({"time": "soon", "timestamp": 1}));
+        let mut data = Map::new();
+        data.insert("message".to_owned(), "no time".into());
+        assert_eq!(parser.extract(&mut data), None);
+    }
+}
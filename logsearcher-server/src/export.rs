@@ -0,0 +1,340 @@
+//! Export of every log of a view in a time range, as NDJSON, CSV or Parquet.
+//!
+//! Rows are read from a database cursor and encoded in chunks sent through a bounded
+//! channel, so a large export never sits in memory and waits for a slow client.
+
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use parquet::{
+    basic::{ConvertedType, Repetition, Type as PhysicalType},
+    data_type::{ByteArray, ByteArrayType},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::Type,
+};
+use tokio::sync::mpsc;
+use tokio_postgres::types::ToSql;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use crate::{
+    handler::{compile_view, pool_error, row_to_json},
+    model::{ExportFormat, ExportQuery, SortOrder},
+    query::SqlWriter,
+    AppState,
+};
+
+/// Rows encoded together before being sent, also the Parquet row group size.
+const CHUNK_ROWS: usize = 10000;
+/// Encoded chunks waiting for the client.
+const CHANNEL_CHUNKS: usize = 4;
+
+type Chunk = Result<Bytes, std::io::Error>;
+
+/// Names of the exported fields, which must be unique to be object keys and Parquet fields:
+/// view columns named like a field before them get a `_2`, `_3`... suffix.
+fn field_names(col_names: Vec<String>) -> Vec<String> {
+    let mut names = vec!["time".to_owned(), "level".to_owned(), "source".to_owned()];
+    for name in col_names {
+        let mut unique = name.clone();
+        let mut suffix = 2;
+        while names.contains(&unique) {
+            unique = format!("{}_{}", name, suffix);
+            suffix += 1;
+        }
+        names.push(unique);
+    }
+    names
+}
+
+pub async fn export_handler(
+    State(data): State<Arc<AppState>>,
+    Json(export_query): Json<ExportQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let log_query = export_query.query;
+    let mut client = data.db.get().await.map_err(pool_error)?;
+    let export_timeout = Duration::from_secs(data.config.query.export_timeout);
+    // $1 and $2 are the time range
+    let mut writer = SqlWriter::bind(3);
+    let view = compile_view(&client, &log_query.table, &log_query.search, &mut writer).await?;
+    let direction = match log_query.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    let sql = format!(
+        "SELECT time, level, source, {} from logs WHERE {} AND time >= $1 AND time <= $2 ORDER BY time {direction}, id {direction}",
+        view.columns, view.condition, direction = direction
+    );
+    let mut encoder = Encoder::new(export_query.format, field_names(view.col_names))
+        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+
+    let (sender, receiver) = mpsc::channel::<Chunk>(CHANNEL_CHUNKS);
+    tokio::spawn(async move {
+        let start = log_query.start.naive_utc();
+        let end = log_query.end.naive_utc();
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&start, &end];
+        params.extend(writer.params());
+        // exports get their own timeout instead of the one of interactive queries
+        let transaction = match client.transaction().await {
+            Ok(transaction) => transaction,
+            Err(error) => {
+                let _ = sender.send(Err(std::io::Error::other(error))).await;
+                return;
+            }
+        };
+        let set_timeout = format!(
+            "SET LOCAL statement_timeout = {}",
+            export_timeout.as_millis()
+        );
+        if let Err(error) = transaction.batch_execute(&set_timeout).await {
+            let _ = sender.send(Err(std::io::Error::other(error))).await;
+            return;
+        }
+        let rows = match transaction.query_raw(&sql, params).await {
+            Ok(rows) => rows,
+            Err(error) => {
+                let _ = sender.send(Err(std::io::Error::other(error))).await;
+                return;
+            }
+        };
+        tokio::pin!(rows);
+        let mut chunk = Vec::with_capacity(CHUNK_ROWS);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) => chunk.push(row_to_json(&row, view.col_number)),
+                Err(error) => {
+                    let _ = sender.send(Err(std::io::Error::other(error))).await;
+                    return;
+                }
+            }
+            if chunk.len() < CHUNK_ROWS {
+                continue;
+            }
+            let encoded = encoder.encode(&chunk).map(Bytes::from);
+            chunk.clear();
+            if sender.send(encoded).await.is_err() {
+                return;
+            }
+        }
+        let encoded = encoder.encode(&chunk).and_then(|mut bytes| {
+            bytes.extend(encoder.finish()?);
+            Ok(Bytes::from(bytes))
+        });
+        let _ = sender.send(encoded).await;
+    });
+
+    let (content_type, extension) = match export_query.format {
+        ExportFormat::Ndjson => ("application/x-ndjson", "ndjson"),
+        ExportFormat::Csv => ("text/csv", "csv"),
+        ExportFormat::Parquet => ("application/vnd.apache.parquet", "parquet"),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    log_query.table.replace(['"', '\\'], "_"),
+                    extension
+                ),
+            ),
+        ],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    ))
+}
+
+/// Text of a value in flat formats, JSON for anything else than a string.
+fn flat_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(text) => Some(text.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// Parquet writes to this buffer, which is drained after each row group.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Encoder {
+    Ndjson {
+        names: Vec<String>,
+    },
+    Csv {
+        names: Vec<String>,
+        header_written: bool,
+    },
+    Parquet {
+        buffer: SharedBuffer,
+        writer: Box<SerializedFileWriter<SharedBuffer>>,
+    },
+}
+
+impl Encoder {
+    fn new(
+        format: ExportFormat,
+        names: Vec<String>,
+    ) -> Result<Self, parquet::errors::ParquetError> {
+        Ok(match format {
+            ExportFormat::Ndjson => Encoder::Ndjson { names },
+            ExportFormat::Csv => Encoder::Csv {
+                names,
+                header_written: false,
+            },
+            ExportFormat::Parquet => {
+                let mut fields = Vec::new();
+                for name in &names {
+                    fields.push(Arc::new(
+                        Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY)
+                            .with_converted_type(ConvertedType::UTF8)
+                            .with_repetition(Repetition::OPTIONAL)
+                            .build()?,
+                    ));
+                }
+                let schema = Type::group_type_builder("logs")
+                    .with_fields(fields)
+                    .build()?;
+                let properties = WriterProperties::builder()
+                    .set_compression(parquet::basic::Compression::SNAPPY)
+                    .build();
+                let buffer = SharedBuffer::default();
+                let writer = SerializedFileWriter::new(
+                    buffer.clone(),
+                    Arc::new(schema),
+                    Arc::new(properties),
+                )?;
+                Encoder::Parquet {
+                    buffer,
+                    writer: Box::new(writer),
+                }
+            }
+        })
+    }
+
+    fn encode(&mut self, rows: &[Vec<serde_json::Value>]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Encoder::Ndjson { names } => {
+                let mut out = Vec::new();
+                for row in rows {
+                    let object: serde_json::Map<String, serde_json::Value> =
+                        names.iter().cloned().zip(row.iter().cloned()).collect();
+                    serde_json::to_writer(&mut out, &object)?;
+                    out.push(b'\n');
+                }
+                Ok(out)
+            }
+            Encoder::Csv {
+                names,
+                header_written,
+            } => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                if !*header_written {
+                    writer.write_record(names.iter())?;
+                    *header_written = true;
+                }
+                for row in rows {
+                    writer.write_record(
+                        row.iter()
+                            .map(|value| flat_value(value).unwrap_or_default()),
+                    )?;
+                }
+                writer.into_inner().map_err(|error| error.into_error())
+            }
+            Encoder::Parquet { buffer, writer } => {
+                if rows.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let mut row_group = writer.next_row_group().map_err(std::io::Error::other)?;
+                let mut index = 0;
+                while let Some(mut column) =
+                    row_group.next_column().map_err(std::io::Error::other)?
+                {
+                    let mut values = Vec::new();
+                    let mut definitions = Vec::new();
+                    for row in rows {
+                        match row.get(index).and_then(flat_value) {
+                            Some(text) => {
+                                values.push(ByteArray::from(text.into_bytes()));
+                                definitions.push(1);
+                            }
+                            None => definitions.push(0),
+                        }
+                    }
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, Some(&definitions), None)
+                        .map_err(std::io::Error::other)?;
+                    column.close().map_err(std::io::Error::other)?;
+                    index += 1;
+                }
+                row_group.close().map_err(std::io::Error::other)?;
+                Ok(buffer.take())
+            }
+        }
+    }
+
+    /// Bytes ending the export, the Parquet footer.
+    fn finish(self) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Encoder::Parquet { buffer, writer } => {
+                writer.close().map_err(std::io::Error::other)?;
+                Ok(buffer.take())
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_names_are_unique() {
+        let names = field_names(
+            ["level", "host", "host", "level_2", "time"]
+                .map(str::to_owned)
+                .to_vec(),
+        );
+        assert_eq!(
+            names,
+            [
+                "time",
+                "level",
+                "source",
+                "level_2",
+                "host",
+                "host_2",
+                "level_2_2",
+                "time_2"
+            ]
+        );
+    }
+}
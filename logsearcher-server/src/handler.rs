@@ -1,10 +1,14 @@
-use std::{cmp::max, sync::Arc};
+use std::{cmp::max, collections::HashSet, sync::Arc};
 
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 use chrono::NaiveDateTime;
+use lazy_static::lazy_static;
+use regex::Regex;
+use tokio_postgres::{error::SqlState, types::ToSql};
 
 use crate::{
-    model::{LogQuery, ViewQuery},
+    model::{Cursor, LogPage, LogQuery, SortOrder, ViewQuery},
+    query::{self, SqlWriter},
     AppState,
 };
 
@@ -19,192 +23,305 @@ pub async fn health_checker_handler() -> impl IntoResponse {
     Json(json_response)
 }
 
-pub async fn upsert_columns_and_filters(
-    data: &Arc<AppState>,
-    column_names: &Vec<String>,
-    columns_queries: &Vec<String>,
+/// Columns no view uses anymore.
+pub const DELETE_ORPHAN_COLUMNS: &str = "DELETE FROM cols WHERE NOT EXISTS (SELECT 1 FROM column_filter WHERE column_filter.column_name = cols.name)";
+
+/// View names end up in identifiers such as `<name>_sec_count`, so they are restricted to
+/// lowercase letters, digits and underscores, short enough for the 63 bytes Postgres allows.
+pub fn check_view_name(name: &str) -> Result<(), (StatusCode, String)> {
+    lazy_static! {
+        static ref VIEW_NAME: Regex = Regex::new(r"^[a-z_][a-z0-9_]{0,49}$").unwrap();
+    };
+    if !VIEW_NAME.is_match(name) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "invalid view name {:?}: use up to 50 lowercase letters, digits and underscores, not starting with a digit",
+                name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Map database errors to responses, constraint conflicts being reported as such.
+pub fn db_error(error: tokio_postgres::Error) -> (StatusCode, String) {
+    match error.code() {
+        Some(&SqlState::UNIQUE_VIOLATION) => (StatusCode::CONFLICT, error.to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+    }
+}
+
+/// Map failures to get a pooled connection like database errors.
+pub fn pool_error(error: deadpool_postgres::PoolError) -> (StatusCode, String) {
+    match error {
+        deadpool_postgres::PoolError::Backend(error) => db_error(error),
+        error => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+    }
+}
+
+async fn upsert_columns_and_filters(
+    transaction: &tokio_postgres::Transaction<'_>,
+    column_names: &[String],
+    columns_queries: &[String],
     filter_name: &String,
     filter_query: &String,
 ) -> Result<(), tokio_postgres::Error> {
-    let client = data.db.get().await.unwrap();
-    let values: Vec<String> = column_names
-        .into_iter()
-        .zip(columns_queries)
-        .map(|(name, query)| ["('", name, "','", &query.replace("'", "''"), "')"].join(""))
-        .collect();
-    let _res = client
-        .query(
-            &format!(
-                "INSERT INTO cols (name, query) VALUES {} ON CONFLICT (name) DO UPDATE SET query = EXCLUDED.query",
-                values.join(",")
-            ),
-            &[],
+    transaction
+        .execute(
+            "INSERT INTO cols (name, query) SELECT * FROM UNNEST($1::text[], $2::text[]) ON CONFLICT (name) DO UPDATE SET query = EXCLUDED.query",
+            &[&column_names, &columns_queries],
         )
         .await?;
-
-    let _res = client
-        .query(
+    transaction
+        .execute(
             "DELETE FROM column_filter WHERE filter_name = $1",
             &[filter_name],
         )
         .await?;
-    let filter_column_values: Vec<String> = column_names
-        .into_iter()
-        .enumerate()
-        .map(|(idx, name)| format!("('{}', '{}', {})", name, filter_name, idx))
-        .collect();
-    let _res = client
-        .query(
-            &format!(
-                "INSERT INTO column_filter (column_name, filter_name, idx) VALUES {}",
-                filter_column_values.join(",")
-            ),
-            &[],
+    transaction
+        .execute(
+            "INSERT INTO column_filter (column_name, filter_name, idx) SELECT name, $2, (idx - 1)::int FROM UNNEST($1::text[]) WITH ORDINALITY AS t(name, idx)",
+            &[&column_names, filter_name],
         )
         .await?;
-    let _res = client
-        .query(
-            &format!(
-                "INSERT INTO filters (name, query) VALUES ('{}', '{}') ON CONFLICT (name) DO UPDATE SET query = EXCLUDED.query",
-                filter_name, filter_query.replace("'", "''")
-            ),
-            &[],
+    transaction
+        .execute(
+            "INSERT INTO filters (name, query) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET query = EXCLUDED.query",
+            &[filter_name, filter_query],
         )
         .await?;
+    transaction.execute(DELETE_ORPHAN_COLUMNS, &[]).await?;
     Ok(())
 }
 
+/// Continuous aggregates of a view: name suffix, bucket width and refresh schedule.
+const AGGREGATES: [(&str, &str, &str); 2] = [
+    ("sec_count", "1s", "10 seconds"),
+    ("min_count", "1 minute", "10 minute"),
+];
+
 pub async fn create_view(
     data: &Arc<AppState>,
     columns_queries: Vec<String>,
     column_names: Vec<String>,
     filter_name: String,
     filter_query: String,
-) -> Result<(StatusCode, String), tokio_postgres::Error> {
+    filter: query::Expr,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    // continuous aggregates cannot take parameters, so the filter is inlined with escaped literals
+    let filter_sql = filter.to_sql(&mut SqlWriter::inline());
+    let mut client = data.db.get().await.map_err(pool_error)?;
+    // the view and its aggregates are replaced together, the aggregates being created empty
+    // since they cannot be filled within a transaction
+    let transaction = client.transaction().await.map_err(db_error)?;
     upsert_columns_and_filters(
-        data,
+        &transaction,
         &column_names,
         &columns_queries,
         &filter_name,
         &filter_query,
     )
-    .await?;
-    let client = data.db.get().await.unwrap();
-    let _res = client
-        .batch_execute(&format!(
-            "DROP MATERIALIZED VIEW IF EXISTS {}_sec_count; DROP MATERIALIZED VIEW IF EXISTS {}_min_count; ",
-            filter_name, filter_name
-        ))
-        .await?;
-    let _res = client
-    .query(
-        &format!(
-            "CREATE MATERIALIZED VIEW {}_sec_count (time_bucket, count) WITH (timescaledb.continuous) AS SELECT time_bucket('1s', time), COUNT(*) from logs where {} GROUP BY time_bucket('1s', time)",
-            filter_name, filter_query,
-        ),
-        &[],
-    )
-    .await?;
-    let _res = client
-    .query(
-        &format!(
-            "CREATE MATERIALIZED VIEW {}_min_count (time_bucket, count) WITH (timescaledb.continuous) AS SELECT time_bucket('1 minute', time), COUNT(*) from logs where {} GROUP BY time_bucket('1 minute', time)",
-            filter_name, filter_query,
-        ),
-        &[],
-    ).await?;
-    let _res = client
-        .query(
-            &format!(
-                "SELECT add_continuous_aggregate_policy('{}_min_count',
-            start_offset => null,
-            end_offset => null,
-            schedule_interval => INTERVAL '10 minute');",
-                filter_name,
-            ),
-            &[],
-        )
-        .await?;
-    let _res = client
-        .query(
-            &format!(
-                "SELECT add_continuous_aggregate_policy('{}_sec_count',
-        start_offset => null,
-        end_offset => null,
-        schedule_interval => INTERVAL '10 seconds');",
-                filter_name,
-            ),
-            &[],
-        )
-        .await?;
-    return Ok((StatusCode::CREATED, "{}".to_string()));
+    .await
+    .map_err(db_error)?;
+    for (suffix, bucket, schedule) in AGGREGATES {
+        transaction
+            .batch_execute(&format!(
+                "DROP MATERIALIZED VIEW IF EXISTS {name}_{suffix};
+                CREATE MATERIALIZED VIEW {name}_{suffix} (time_bucket, count) WITH (timescaledb.continuous) AS SELECT time_bucket('{bucket}', time), COUNT(*) from logs where {filter_sql} GROUP BY time_bucket('{bucket}', time) WITH NO DATA;
+                SELECT add_continuous_aggregate_policy('{name}_{suffix}',
+                    start_offset => null,
+                    end_offset => null,
+                    schedule_interval => INTERVAL '{schedule}');",
+                name = filter_name,
+            ))
+            .await
+            .map_err(db_error)?;
+    }
+    transaction.commit().await.map_err(db_error)?;
+    for (suffix, _, _) in AGGREGATES {
+        // the refresh policy fills the aggregates anyway, this only makes them ready sooner
+        if let Err(error) = client
+            .batch_execute(&format!(
+                "CALL refresh_continuous_aggregate('{}_{}', NULL, NULL)",
+                filter_name, suffix
+            ))
+            .await
+        {
+            eprintln!("cannot refresh {}_{}: {}", filter_name, suffix, error);
+        }
+    }
+    Ok((StatusCode::CREATED, "{}".to_string()))
 }
 
-pub async fn get_logs(
-    data: &Arc<AppState>,
-    table: String,
-    start: chrono::NaiveDateTime,
-    end: chrono::NaiveDateTime,
-    offset: i64,
-) -> Result<Vec<Vec<serde_json::Value>>, (StatusCode, String)> {
-    let client = data.db.get().await.unwrap();
+/// Filter and columns of a view, compiled with the parameters of a query.
+pub struct CompiledView {
+    pub col_number: usize,
+    pub col_names: Vec<String>,
+    /// Selected columns, to put after `time, level, source`.
+    pub columns: String,
+    /// Condition on `logs` matching the view and searched terms.
+    pub condition: String,
+}
 
+pub async fn compile_view(
+    client: &deadpool_postgres::Client,
+    table: &String,
+    search: &str,
+    writer: &mut SqlWriter,
+) -> Result<CompiledView, (StatusCode, String)> {
+    check_view_name(table)?;
     let row = client
-        .query_one(
-            "SELECT COUNT(*), filters.query, array_agg(cols.query ORDER BY idx) from column_filter JOIN filters ON filters.name = column_filter.filter_name JOIN cols ON cols.name = column_filter.column_name WHERE filters.name = $1 GROUP BY filters.name, filters.query",
-            &[&table],
+        .query_opt(
+            "SELECT COUNT(*), filters.query, array_agg(cols.query ORDER BY idx), array_agg(cols.name ORDER BY idx) from column_filter JOIN filters ON filters.name = column_filter.filter_name JOIN cols ON cols.name = column_filter.column_name WHERE filters.name = $1 GROUP BY filters.name, filters.query",
+            &[table],
         )
         .await
-        .unwrap();
+        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("unknown view {}", table)))?;
 
     let col_number: usize = row.get::<_, i64>(0) as usize;
     let filter_query: String = row.get::<_, String>(1);
     let column_queries: Vec<String> = row.get::<_, Vec<String>>(2);
+    let col_names: Vec<String> = row.get::<_, Vec<String>>(3);
+
+    let mut column_sql = Vec::new();
+    for column_query in &column_queries {
+        let field = query::parse_column(column_query)
+            .map_err(|error| (StatusCode::BAD_REQUEST, format!("column {}", error)))?;
+        column_sql.push(field.to_sql(writer));
+    }
+    let filter_sql = query::parse_filter(&filter_query)
+        .map_err(|error| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "filter of view {} {}; save the view again with its filter in the query language",
+                    table, error
+                ),
+            )
+        })?
+        .to_sql(writer);
+    let search_sql = query::parse(search)
+        .map_err(|error| (StatusCode::BAD_REQUEST, format!("search {}", error)))?
+        .to_sql(writer);
+    Ok(CompiledView {
+        col_number,
+        col_names,
+        columns: column_sql.join(","),
+        condition: format!("{} AND {}", filter_sql, search_sql),
+    })
+}
+
+/// Lay out a `SELECT time, level, source, <view columns>` row as returned to clients.
+pub fn row_to_json(r: &tokio_postgres::Row, col_number: usize) -> Vec<serde_json::Value> {
+    let mut ret_line: Vec<serde_json::Value> = Vec::new();
+    ret_line.push(match r.try_get::<_, NaiveDateTime>(0) {
+        Ok(val) => val.to_string().into(),
+        Err(_) => "".into(),
+    });
+    ret_line.push(r.get::<_, Option<String>>(1).into());
+    ret_line.push(r.get::<_, Option<String>>(2).into());
+    for i in 3..col_number + 3 {
+        let json_value: serde_json::Value = match r.try_get::<_, serde_json::Value>(i) {
+            Ok(val) => val,
+            Err(_) => match r.try_get::<_, f64>(i) {
+                Ok(val) => val.into(),
+                Err(_) => match r.try_get::<_, Vec<String>>(i) {
+                    Ok(strval) => strval.into(),
+                    Err(_) => match r.try_get::<_, String>(i) {
+                        Ok(strval) => strval.into(),
+                        Err(_) => serde_json::from_str("null").unwrap(),
+                    },
+                },
+            },
+        };
+        ret_line.push(json_value);
+    }
+    ret_line
+}
+
+pub async fn get_logs(
+    data: &Arc<AppState>,
+    log_query: &LogQuery,
+) -> Result<LogPage, (StatusCode, String)> {
+    let client = data.db.get().await.map_err(pool_error)?;
+
+    let start = log_query.start.naive_utc();
+    let end = log_query.end.naive_utc();
+    let limit = log_query
+        .limit
+        .unwrap_or(data.config.query.default_page_size)
+        .clamp(1, data.config.query.max_page_size);
+    // one more row than asked tells whether there is a next page
+    let fetch_limit = limit + 1;
+    let cursor = match &log_query.cursor {
+        Some(cursor) => match Cursor::decode(cursor) {
+            Some(cursor) if cursor.order == log_query.order => Some(cursor),
+            Some(_) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "cursor was issued for the other sort order".to_owned(),
+                ))
+            }
+            None => return Err((StatusCode::BAD_REQUEST, "invalid cursor".to_owned())),
+        },
+        None => None,
+    };
+    let (direction, comparison) = match log_query.order {
+        SortOrder::Asc => ("ASC", ">"),
+        SortOrder::Desc => ("DESC", "<"),
+    };
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&start, &end, &fetch_limit];
+    let seek_sql = match &cursor {
+        Some(cursor) => {
+            params.push(&cursor.time);
+            params.push(&cursor.id);
+            format!("(time, id) {} ($4, $5)", comparison)
+        }
+        None => "true".to_owned(),
+    };
+
+    // values from the view follow the time range, limit and cursor
+    let mut writer = SqlWriter::bind(params.len() + 1);
+    let view = compile_view(&client, &log_query.table, &log_query.search, &mut writer).await?;
+    params.extend(writer.params());
     let row = client
         .query(
             &format!(
-                "SELECT time, level, {} from logs WHERE {} AND time >= '{}'::TIMESTAMP AND time <= '{}'::TIMESTAMP LIMIT 40 OFFSET {}",
-                column_queries.join(","), filter_query, start, end, offset
+                "SELECT time, level, source, {}, id from logs WHERE {} AND {} AND time >= $1 AND time <= $2 ORDER BY time {direction}, id {direction} LIMIT $3",
+                view.columns, view.condition, seek_sql, direction = direction
             ),
-            &[],
+            &params,
         )
         .await;
 
-    if row.is_err() {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            row.unwrap_err().to_string(),
-        ));
-    }
-
-    let rows = row.unwrap();
+    let rows = match row {
+        Ok(rows) => rows,
+        Err(error) => return Err((StatusCode::INTERNAL_SERVER_ERROR, error.to_string())),
+    };
+    let has_more = rows.len() as i64 > limit;
+    let mut next_cursor = None;
     let mut ret_val: Vec<Vec<serde_json::Value>> = Vec::new();
-    for r in rows {
-        let mut ret_line: Vec<serde_json::Value> = Vec::new();
-        ret_line.push(match r.try_get::<_, NaiveDateTime>(0) {
-            Ok(val) => val.to_string().into(),
-            Err(_) => "".into(),
-        });
-        ret_line.push(r.get::<_, Option<String>>(1).into());
-        for i in 2..col_number + 2 {
-            let json_value: serde_json::Value = match r.try_get::<_, serde_json::Value>(i) {
-                Ok(val) => val,
-                Err(_) => match r.try_get::<_, f64>(i) {
-                    Ok(val) => val.into(),
-                    Err(_) => match r.try_get::<_, Vec<String>>(i) {
-                        Ok(strval) => strval.into(),
-                        Err(_) => match r.try_get::<_, String>(i) {
-                            Ok(strval) => strval.into(),
-                            Err(_) => serde_json::from_str("null").unwrap(),
-                        },
-                    },
-                },
-            };
-            ret_line.push(json_value);
+    for r in rows.iter().take(limit as usize) {
+        if let (Ok(time), Ok(id)) = (
+            r.try_get::<_, NaiveDateTime>(0),
+            r.try_get::<_, i64>(view.col_number + 3),
+        ) {
+            next_cursor = Some(Cursor {
+                order: log_query.order,
+                time,
+                id,
+            });
         }
-        ret_val.push(ret_line);
+        ret_val.push(row_to_json(r, view.col_number));
     }
-    Ok(ret_val)
+    Ok(LogPage {
+        rows: ret_val,
+        cursor: next_cursor
+            .filter(|_| has_more)
+            .map(|cursor| cursor.encode()),
+    })
 }
 
 pub async fn get_density(
@@ -213,7 +330,9 @@ pub async fn get_density(
     start: chrono::NaiveDateTime,
     end: chrono::NaiveDateTime,
 ) -> Result<Vec<serde_json::Number>, (StatusCode, String)> {
-    let client = data.db.get().await.unwrap();
+    let client = data.db.get().await.map_err(pool_error)?;
+    // resolving the view checks it exists before its name is used in a query
+    let view = compile_view(&client, &table, "", &mut SqlWriter::inline()).await?;
     let interval_millis = (end - start).num_milliseconds();
     let interval_micro = (end - start).num_microseconds();
     let interval_str = match interval_micro {
@@ -224,8 +343,8 @@ pub async fn get_density(
         0..=100000 => client
         .query(
             &format!(
-                "SELECT COUNT(*)::bigint from {} WHERE time > '{}'::TIMESTAMP AND time < '{}'::TIMESTAMP GROUP BY time_bucket_gapfill('{}', time)",
-                table, start, end, interval_str
+                "SELECT COUNT(*)::bigint from logs WHERE {} AND time > '{}'::TIMESTAMP AND time < '{}'::TIMESTAMP GROUP BY time_bucket_gapfill('{}', time)",
+                view.condition, start, end, interval_str
             ),
             &[],
         )
@@ -245,14 +364,10 @@ pub async fn get_density(
         )
         .await,
     };
-    if row.is_err() {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            row.unwrap_err().to_string(),
-        ));
-    }
-
-    let rows = row.unwrap();
+    let rows = match row {
+        Ok(rows) => rows,
+        Err(error) => return Err((StatusCode::INTERNAL_SERVER_ERROR, error.to_string())),
+    };
     let mut ret_val: Vec<serde_json::Number> = Vec::new();
     for r in rows {
         ret_val.push(r.try_get::<_, i64>(0).unwrap_or(0).into());
@@ -275,55 +390,69 @@ pub async fn density_handler(
         Ok(vec) => vec,
         Err((code, json)) => return Err((code, json)),
     };
-    return Ok(Json(logs));
+    Ok(Json(logs))
 }
 
 pub async fn logs_handler(
     State(data): State<Arc<AppState>>,
     log_query: Json<LogQuery>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let logs = match get_logs(
-        &data,
-        log_query.table.to_owned(),
-        log_query.start.naive_utc(),
-        log_query.end.naive_utc(),
-        log_query.offset,
-    )
-    .await
-    {
+    let logs = match get_logs(&data, &log_query).await {
         Ok(vec) => vec,
         Err((code, json)) => return Err((code, json)),
     };
-    return Ok(Json(logs));
+    Ok(Json(logs))
 }
 
-pub async fn view_handler(
-    State(data): State<Arc<AppState>>,
-    log_query: Json<ViewQuery>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let filter_name = log_query.filter.name.to_owned();
-    let filter_query = log_query.filter.query.to_owned();
-    let (names, queries) = log_query
-        .0
+/// Validate a view definition, then create or replace it.
+pub async fn save_view(
+    data: &Arc<AppState>,
+    view_query: ViewQuery,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let filter_name = view_query.filter.name.to_owned();
+    let filter_query = view_query.filter.query.to_owned();
+    let filter = query::parse_filter(&filter_query)
+        .map_err(|error| (StatusCode::BAD_REQUEST, format!("filter {}", error)))?;
+    let mut seen = HashSet::new();
+    for column in &view_query.columns {
+        if !seen.insert(column.name.as_str()) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("column {} is listed more than once", column.name),
+            ));
+        }
+        query::parse_column(&column.query).map_err(|error| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("column {} {}", column.name, error),
+            )
+        })?;
+    }
+    let (names, queries) = view_query
         .columns
         .into_iter()
         .map(|c| (c.name, c.query))
         .unzip();
-    let filter_name = if filter_name.len() == 0 {
+    let filter_name = if filter_name.is_empty() {
         "logs".to_owned()
     } else {
         filter_name
     };
-    match create_view(&data, queries, names, filter_name, filter_query).await {
-        Ok(vec) => Ok(vec),
-        Err(error) => return Err((StatusCode::INTERNAL_SERVER_ERROR, error.to_string())),
-    }
+    check_view_name(&filter_name)?;
+    create_view(data, queries, names, filter_name, filter_query, filter).await
+}
+
+pub async fn view_handler(
+    State(data): State<Arc<AppState>>,
+    log_query: Json<ViewQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    save_view(&data, log_query.0).await
 }
 
 pub async fn list_views(
     State(data): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let client = data.db.get().await.unwrap();
+    let client = data.db.get().await.map_err(pool_error)?;
     match client.query("SELECT filters.name, array_agg(cols.name ORDER BY idx) from filters JOIN column_filter ON filters.name = column_filter.filter_name JOIN cols ON cols.name = column_filter.column_name GROUP BY filters.name;", &[]).await {
         Ok(rows) => Ok(Json(
             rows.into_iter()
@@ -1,6 +1,11 @@
+mod config;
+mod export;
 mod handler;
 mod model;
+mod query;
 mod route;
+mod tail;
+mod views;
 
 use std::sync::Arc;
 
@@ -8,39 +13,51 @@ use axum::http::{
     header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
     HeaderValue, Method,
 };
-use deadpool_postgres::*;
-use tokio_postgres::NoTls;
-
+use config::{Config, ConfigError};
 use dotenv::dotenv;
 use route::create_router;
 use tower_http::cors::CorsLayer;
 
 pub struct AppState {
     db: deadpool_postgres::Pool,
+    config: Config,
+}
+
+fn exit_on_config_error<T>(error: ConfigError) -> T {
+    eprintln!("{}", error);
+    std::process::exit(2);
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
-    let mut cfg = Config::new();
-    cfg.host = Some("localhost".to_owned());
-    cfg.user = Some("postgres".to_owned());
-    cfg.dbname = Some("postgres".to_owned());
-    cfg.password = Some("test".to_owned());
-    let pool = cfg.create_pool(None, NoTls).unwrap();
+    let config = Config::load().unwrap_or_else(exit_on_config_error);
+    let pool = config.create_pool().unwrap_or_else(exit_on_config_error);
+    let listen = config.listen_address().unwrap_or_else(exit_on_config_error);
+    let origins: Vec<HeaderValue> = config.cors_origins().unwrap_or_else(exit_on_config_error);
 
     let cors = CorsLayer::new()
-        .allow_origin("http://localhost:8000".parse::<HeaderValue>().unwrap())
-        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_origin(origins)
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
         .allow_credentials(true)
         .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
 
-    let app = create_router(Arc::new(AppState { db: pool.clone() })).layer(cors);
+    let app = create_router(Arc::new(AppState { db: pool, config })).layer(cors);
 
-    println!("Server started successfully");
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .unwrap_or_else(|error| {
+            eprintln!("cannot listen on {}: {}", listen, error);
+            std::process::exit(1);
+        });
+    println!("Server started successfully on {}", listen);
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
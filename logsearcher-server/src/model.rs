@@ -1,4 +1,5 @@
-use chrono::Utc;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -7,22 +8,48 @@ pub struct LogQuery {
     pub end: chrono::DateTime<Utc>,
     #[serde(default = "default_table")]
     pub table: String,
-    #[serde(default = "default_offset")]
-    pub offset: i64,
-}
-
-fn default_offset() -> i64 {
-    0
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Number of rows per page, defaulting to and capped by the `query` settings.
+    pub limit: Option<i64>,
+    /// Cursor returned with the previous page, to get the next one.
+    pub cursor: Option<String>,
+    /// Words, phrases and `prefix*` terms to look for in the view, in the query language.
+    #[serde(default)]
+    pub search: String,
 }
 
 fn default_filter() -> String {
-    "true".to_owned()
+    "*".to_owned()
 }
 
 fn default_table() -> String {
     "logs".to_owned()
 }
 
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Ndjson,
+    Csv,
+    Parquet,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportQuery {
+    #[serde(flatten)]
+    pub query: LogQuery,
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TailQuery {
+    #[serde(default = "default_table")]
+    pub table: String,
+    #[serde(default)]
+    pub search: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ColumnDef {
     pub name: String,
@@ -41,3 +68,123 @@ pub struct ViewQuery {
     pub columns: Vec<ColumnDef>,
     pub filter: FilterDef,
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ViewDef {
+    pub name: String,
+    pub query: String,
+    pub columns: Vec<ColumnDef>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RenameQuery {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ColumnQuery {
+    pub query: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogPage {
+    pub rows: Vec<Vec<serde_json::Value>>,
+    /// Set when there may be more rows after this page.
+    pub cursor: Option<String>,
+}
+
+/// Position after the last row of a page, handed to clients as an opaque string.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub order: SortOrder,
+    pub time: NaiveDateTime,
+    pub id: i64,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let order = match self.order {
+            SortOrder::Asc => "a",
+            SortOrder::Desc => "d",
+        };
+        URL_SAFE_NO_PAD.encode(format!(
+            "{}:{}:{}",
+            order,
+            self.time.and_utc().timestamp_micros(),
+            self.id
+        ))
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+        let mut parts = decoded.split(':');
+        let order = match parts.next()? {
+            "a" => SortOrder::Asc,
+            "d" => SortOrder::Desc,
+            _ => return None,
+        };
+        let time = chrono::DateTime::from_timestamp_micros(parts.next()?.parse().ok()?)?;
+        let id = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            order,
+            time: time.naive_utc(),
+            id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        for (order, micros, id) in [
+            (SortOrder::Desc, 1_700_000_000_123_456, 42),
+            (SortOrder::Asc, 0, 1),
+            (SortOrder::Asc, -86_400_000_000, i64::MAX),
+        ] {
+            let cursor = Cursor {
+                order,
+                time: DateTime::from_timestamp_micros(micros).unwrap().naive_utc(),
+                id,
+            };
+            let encoded = cursor.encode();
+            assert!(encoded
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+            assert_eq!(Cursor::decode(&encoded), Some(cursor));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        let encode = |text: &str| URL_SAFE_NO_PAD.encode(text);
+        for cursor in [
+            String::new(),
+            "not base64!".to_owned(),
+            encode("a:1700000000000000"),
+            encode("x:1700000000000000:1"),
+            encode("a:soon:1"),
+            encode("a:1700000000000000:one"),
+            encode("a:1700000000000000:1:2"),
+            encode(&format!("d:{}:1", i64::MAX)),
+            URL_SAFE_NO_PAD.encode([0xff, 0xfe]),
+        ] {
+            assert_eq!(Cursor::decode(&cursor), None, "{}", cursor);
+        }
+    }
+}
@@ -0,0 +1,1090 @@
+//! Log query language.
+//!
+//! View filters and columns are written in a small language instead of raw SQL,
+//! for instance `level:ERROR AND host:"some-host" AND cpu>5 AND "timeout"`.
+//! They are parsed into an [`Expr`] (or a [`Field`] for columns) and compiled to SQL
+//! where every user supplied value goes through a [`SqlWriter`], so nothing typed
+//! by a user reaches the database as SQL.
+//!
+//! - `field:value`, `field=value` and `field!=value` match a field, `field:*` checks it exists
+//! - `field>value`, `>=`, `<` and `<=` compare numerically when the value is a number
+//! - `level` and `source` refer to their columns, `words` and bare terms search indexed words,
+//!   any other field is a dotted path into `logdata` (`logdata.` may prefix it explicitly)
+//! - searched terms are split into words like the consumer does, `"a phrase"` also requires
+//!   the words to follow each other and `prefix*` matches words starting with `prefix`
+//! - terms combine with `AND` (or juxtaposition), `OR`, `NOT` and parentheses
+//! - an empty query or `*` matches everything
+//!
+//! Queries come from any client, so their nesting, number of terms and field paths are
+//! capped, which keeps parsing, compiling and dropping them within the stack.
+
+use std::fmt;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use tokio_postgres::types::ToSql;
+
+/// Deepest nesting of parentheses and `NOT`.
+const MAX_DEPTH: usize = 32;
+
+/// Most terms in a query.
+const MAX_TERMS: usize = 256;
+
+/// Most segments in a field path.
+const MAX_PATH_SEGMENTS: usize = 32;
+
+lazy_static! {
+    /// Same word pattern as the consumer uses to fill `logs.words`.
+    static ref WORD: Regex = Regex::new(r"[\w]+([-_][\w]+)*").unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Character offset in the query where the error was found.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at position {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "!=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Level,
+    Source,
+    Words,
+    /// Path into `logdata`, the whole document when empty.
+    Data(Vec<String>),
+}
+
+impl Field {
+    fn from_name(name: &str) -> Self {
+        match name {
+            "level" => Field::Level,
+            "source" => Field::Source,
+            "words" => Field::Words,
+            "logdata" => Field::Data(Vec::new()),
+            _ => Field::Data(
+                name.strip_prefix("logdata.")
+                    .unwrap_or(name)
+                    .split('.')
+                    .map(str::to_owned)
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Compile the field as a selected column.
+    pub fn to_sql(&self, writer: &mut SqlWriter) -> String {
+        match self {
+            Field::Level => "level".to_owned(),
+            Field::Source => "source".to_owned(),
+            Field::Words => "words".to_owned(),
+            Field::Data(path) if path.is_empty() => "logdata".to_owned(),
+            Field::Data(path) => format!("logdata #> {}", writer.path(path)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// `field:*`
+    Any,
+    Text {
+        raw: String,
+        quoted: bool,
+    },
+}
+
+/// Free text searched in `words`, so that it can use the GIN index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Search {
+    words: Vec<String>,
+    /// Start of a word, for terms ending with `*`.
+    prefix: Option<String>,
+    /// Quoted text of several words, which must also appear as is.
+    phrase: Option<String>,
+}
+
+impl Search {
+    fn new(text: &str, quoted: bool) -> Option<Self> {
+        let mut words: Vec<String> = WORD
+            .find_iter(text)
+            .map(|word| word.as_str().to_owned())
+            .collect();
+        let prefix = match (quoted, text.ends_with('*')) {
+            (false, true) => words.pop(),
+            _ => None,
+        };
+        if words.is_empty() && prefix.is_none() {
+            return None;
+        }
+        let phrase = match quoted && words.len() > 1 {
+            true => Some(text.to_owned()),
+            false => None,
+        };
+        Some(Self {
+            words,
+            prefix,
+            phrase,
+        })
+    }
+
+    fn to_sql(&self, writer: &mut SqlWriter) -> String {
+        let mut conditions = Vec::new();
+        if !self.words.is_empty() {
+            let words: Vec<String> = self
+                .words
+                .iter()
+                .map(|word| writer.value(word, "text"))
+                .collect();
+            conditions.push(format!("words @> ARRAY[{}]", words.join(", ")));
+        }
+        if let Some(prefix) = &self.prefix {
+            let pattern = format!(
+                "{}%",
+                prefix
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            );
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM unnest(words) AS word WHERE word LIKE {})",
+                writer.value(&pattern, "text")
+            ));
+        }
+        if let Some(phrase) = &self.phrase {
+            conditions.push(format!(
+                "strpos(logdata::text, {}) > 0",
+                writer.value(phrase, "text")
+            ));
+        }
+        format!("({})", conditions.join(" AND "))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    All,
+    /// Two or more terms which must all match, kept flat so long queries stay shallow.
+    And(Vec<Expr>),
+    /// Two or more terms of which one must match.
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Search(Search),
+    Compare {
+        field: Field,
+        op: CompareOp,
+        value: Value,
+    },
+}
+
+/// Parse a query, such as the terms searched in a view.
+pub fn parse(query: &str) -> Result<Expr, ParseError> {
+    let tokens = tokenize(query)?;
+    let mut parser = Parser {
+        tokens,
+        index: 0,
+        end: query.chars().count(),
+        terms: 0,
+    };
+    if parser.peek().is_none() {
+        return Ok(Expr::All);
+    }
+    let expr = parser.parse_or(0)?;
+    match parser.next() {
+        None => Ok(expr),
+        Some(token) => Err(token.error(format!("unexpected {}", token.token))),
+    }
+}
+
+/// Parse a stored view filter.
+///
+/// Filters used to be raw SQL, which often still parses as searched words: the old default
+/// `true` would search for the word "true". Filters written in SQL are rejected instead,
+/// which is told from SQL syntax in the filter and SQL keywords among its searched terms;
+/// compared values are left alone, `enabled:true` being a valid filter.
+pub fn parse_filter(query: &str) -> Result<Expr, ParseError> {
+    lazy_static! {
+        /// A comparison with an SQL string literal, such as `level = 'ERROR'`.
+        static ref SQL_LITERAL: Regex = Regex::new(r"[=<>]\s*'[^']*'").unwrap();
+    }
+    let sql_error = |position: usize, found: &str| ParseError {
+        position,
+        message: format!(
+            "{} looks like SQL, filters are written in the query language (`*` matches everything)",
+            found
+        ),
+    };
+    if let Some(literal) = SQL_LITERAL.find(query) {
+        let position = query[..literal.start()].chars().count();
+        return Err(sql_error(position, literal.as_str()));
+    }
+    let tokens = tokenize(query)?;
+    let is_op = |index: Option<usize>| {
+        matches!(
+            index.and_then(|index| tokens.get(index)),
+            Some(Spanned {
+                token: Token::Op(_),
+                ..
+            })
+        )
+    };
+    for (index, spanned) in tokens.iter().enumerate() {
+        if let Token::Word(word) = &spanned.token {
+            // `->` is split in a word ending with `-` and a `>` or `>=` right after it
+            let arrow = word.ends_with('-')
+                && matches!(
+                    tokens.get(index + 1),
+                    Some(Spanned { token: Token::Op(CompareOp::Gt | CompareOp::Ge), position })
+                        if *position == spanned.position + word.chars().count()
+                );
+            let compared = is_op(index.checked_sub(1)) || is_op(Some(index + 1));
+            if arrow || (!compared && is_sql(word)) {
+                return Err(sql_error(spanned.position, &spanned.token.to_string()));
+            }
+        }
+    }
+    parse(query)
+}
+
+/// Searched words of SQL filters which are not meant to be searched.
+fn is_sql(word: &str) -> bool {
+    const KEYWORDS: [&str; 5] = ["true", "false", "null", "like", "ilike"];
+    KEYWORDS
+        .iter()
+        .any(|keyword| word.eq_ignore_ascii_case(keyword))
+        || matches!(word, "and" | "or" | "not")
+        || word.starts_with('\'')
+        || word.ends_with('\'')
+        || word.contains("::")
+}
+
+/// Parse a view column, which is a single field.
+pub fn parse_column(query: &str) -> Result<Field, ParseError> {
+    let tokens = tokenize(query)?;
+    match tokens.as_slice() {
+        [token @ Spanned {
+            token: Token::Word(name) | Token::Quoted(name),
+            ..
+        }] => field(token, name),
+        [] => Err(ParseError {
+            position: 0,
+            message: "expected a field name".to_owned(),
+        }),
+        [_, extra, ..] => Err(extra.error("a column is a single field name".to_owned())),
+        [token] => Err(token.error(format!("expected a field name, found {}", token.token))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Op(CompareOp),
+    Word(String),
+    Quoted(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::LParen => f.write_str("'('"),
+            Token::RParen => f.write_str("')'"),
+            Token::And => f.write_str("AND"),
+            Token::Or => f.write_str("OR"),
+            Token::Not => f.write_str("NOT"),
+            Token::Op(op) => write!(f, "'{}'", op),
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Quoted(text) => write!(f, "\"{}\"", text),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Spanned {
+    token: Token,
+    position: usize,
+}
+
+impl Spanned {
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            position: self.position,
+            message,
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '(' | ')' | '"' | ':' | '=' | '!' | '<' | '>')
+}
+
+fn tokenize(query: &str) -> Result<Vec<Spanned>, ParseError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let position = i;
+        let c = chars[i];
+        let token = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => {
+                i += 1;
+                Token::LParen
+            }
+            ')' => {
+                i += 1;
+                Token::RParen
+            }
+            ':' | '=' => {
+                i += 1;
+                Token::Op(CompareOp::Eq)
+            }
+            '!' | '<' | '>' => {
+                let followed_by_eq = chars.get(i + 1) == Some(&'=');
+                i += if followed_by_eq { 2 } else { 1 };
+                Token::Op(match (c, followed_by_eq) {
+                    ('!', true) => CompareOp::Ne,
+                    ('<', true) => CompareOp::Le,
+                    ('>', true) => CompareOp::Ge,
+                    ('<', false) => CompareOp::Lt,
+                    ('>', false) => CompareOp::Gt,
+                    _ => {
+                        return Err(ParseError {
+                            position,
+                            message: "expected '=' after '!'".to_owned(),
+                        })
+                    }
+                })
+            }
+            '"' => {
+                i += 1;
+                let mut text = String::new();
+                loop {
+                    match chars.get(i) {
+                        None => {
+                            return Err(ParseError {
+                                position,
+                                message: "unterminated quoted string".to_owned(),
+                            })
+                        }
+                        Some('"') => {
+                            i += 1;
+                            break;
+                        }
+                        Some('\\') if i + 1 < chars.len() => {
+                            text.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(c) => {
+                            text.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                Token::Quoted(text)
+            }
+            _ => {
+                let start = i;
+                while i < chars.len() && is_word_char(chars[i]) {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                }
+            }
+        };
+        tokens.push(Spanned { token, position });
+    }
+    Ok(tokens)
+}
+
+fn field(token: &Spanned, name: &str) -> Result<Field, ParseError> {
+    let field = Field::from_name(name);
+    match &field {
+        Field::Data(path) if path.len() > MAX_PATH_SEGMENTS => Err(token.error(format!(
+            "field paths have at most {} segments",
+            MAX_PATH_SEGMENTS
+        ))),
+        _ => Ok(field),
+    }
+}
+
+fn search(token: &Spanned, text: &str, quoted: bool) -> Result<Search, ParseError> {
+    Search::new(text, quoted)
+        .ok_or_else(|| token.error(format!("{} has no word to search", token.token)))
+}
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    index: usize,
+    /// Length of the query, reported when it ends too early.
+    end: usize,
+    /// Terms parsed so far.
+    terms: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|spanned| &spanned.token)
+    }
+
+    fn next(&mut self) -> Option<Spanned> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    /// Position of the next token, or the end of the query.
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map_or(self.end, |spanned| spanned.position)
+    }
+
+    fn parse_or(&mut self, depth: usize) -> Result<Expr, ParseError> {
+        let mut terms = vec![self.parse_and(depth)?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            terms.push(self.parse_and(depth)?);
+        }
+        Ok(match terms.len() {
+            1 => terms.pop().unwrap(),
+            _ => Expr::Or(terms),
+        })
+    }
+
+    fn parse_and(&mut self, depth: usize) -> Result<Expr, ParseError> {
+        let mut terms = vec![self.parse_not(depth)?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                }
+                // juxtaposed terms are implicitly combined with AND
+                Some(Token::Word(_) | Token::Quoted(_) | Token::LParen | Token::Not) => {}
+                _ => break,
+            }
+            terms.push(self.parse_not(depth)?);
+        }
+        Ok(match terms.len() {
+            1 => terms.pop().unwrap(),
+            _ => Expr::And(terms),
+        })
+    }
+
+    fn parse_not(&mut self, depth: usize) -> Result<Expr, ParseError> {
+        if self.peek() == Some(&Token::Not) {
+            self.check_depth(depth + 1)?;
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_not(depth + 1)?)));
+        }
+        self.parse_term(depth)
+    }
+
+    fn check_depth(&self, depth: usize) -> Result<(), ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(ParseError {
+                position: self.position(),
+                message: format!(
+                    "parentheses and NOT are nested more than {} deep",
+                    MAX_DEPTH
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    fn parse_term(&mut self, depth: usize) -> Result<Expr, ParseError> {
+        if self.peek() == Some(&Token::LParen) {
+            self.check_depth(depth + 1)?;
+        } else if self.peek().is_some() {
+            self.terms += 1;
+            if self.terms > MAX_TERMS {
+                return Err(ParseError {
+                    position: self.position(),
+                    message: format!("a query has at most {} terms", MAX_TERMS),
+                });
+            }
+        }
+        let spanned = match self.next() {
+            Some(spanned) => spanned,
+            None => return Err(self.end_error("expected a term")),
+        };
+        match spanned.token {
+            Token::LParen => {
+                let expr = self.parse_or(depth + 1)?;
+                match self.next() {
+                    Some(Spanned {
+                        token: Token::RParen,
+                        ..
+                    }) => Ok(expr),
+                    Some(token) => Err(token.error(format!("expected ')', found {}", token.token))),
+                    None => Err(self.end_error("expected ')'")),
+                }
+            }
+            Token::Word(ref name) | Token::Quoted(ref name) => match self.peek() {
+                Some(Token::Op(op)) => {
+                    let op = *op;
+                    let op_token = self.next().unwrap();
+                    let field = field(&spanned, name)?;
+                    self.parse_comparison(&spanned, field, op, &op_token)
+                }
+                _ if spanned.token == Token::Word("*".to_owned()) => Ok(Expr::All),
+                _ => {
+                    let quoted = matches!(spanned.token, Token::Quoted(_));
+                    Ok(Expr::Search(search(&spanned, name, quoted)?))
+                }
+            },
+            _ => Err(spanned.error(format!("expected a term, found {}", spanned.token))),
+        }
+    }
+
+    fn parse_comparison(
+        &mut self,
+        field_token: &Spanned,
+        field: Field,
+        op: CompareOp,
+        op_token: &Spanned,
+    ) -> Result<Expr, ParseError> {
+        let value_token = match self.next() {
+            Some(token) => token,
+            None => return Err(self.end_error(&format!("expected a value after '{}'", op))),
+        };
+        let value = match &value_token.token {
+            Token::Word(raw) if raw == "*" => Value::Any,
+            Token::Word(raw) => Value::Text {
+                raw: raw.clone(),
+                quoted: false,
+            },
+            Token::Quoted(raw) => Value::Text {
+                raw: raw.clone(),
+                quoted: true,
+            },
+            token => {
+                return Err(
+                    value_token.error(format!("expected a value after '{}', found {}", op, token))
+                )
+            }
+        };
+        if value == Value::Any && op != CompareOp::Eq {
+            return Err(op_token.error(format!("'*' can only follow ':', not '{}'", op)));
+        }
+        if field == Field::Words && !matches!(op, CompareOp::Eq | CompareOp::Ne) {
+            return Err(op_token.error(format!("'words' cannot be compared with '{}'", op)));
+        }
+        if field == Field::Data(Vec::new()) {
+            return Err(field_token.error("'logdata' needs a field path".to_owned()));
+        }
+        if let (Field::Data(_), Value::Text { raw, quoted: false }) = (&field, &value) {
+            let ordered = !matches!(op, CompareOp::Eq | CompareOp::Ne);
+            // Rust reads `inf`, `NaN` and `1e999` as numbers, which Postgres rejects
+            if ordered && raw.parse::<f64>().is_ok_and(|number| !number.is_finite()) {
+                return Err(value_token.error(format!("{} is not a finite number", raw)));
+            }
+        }
+        match (&field, &value) {
+            (Field::Words, Value::Text { raw, quoted }) => {
+                let search = Expr::Search(search(&value_token, raw, *quoted)?);
+                match op {
+                    CompareOp::Eq => Ok(search),
+                    _ => Ok(Expr::Not(Box::new(search))),
+                }
+            }
+            _ => Ok(Expr::Compare { field, op, value }),
+        }
+    }
+
+    fn end_error(&self, message: &str) -> ParseError {
+        ParseError {
+            position: self.end,
+            message: format!("{}, found end of query", message),
+        }
+    }
+}
+
+/// Collects the values of compiled expressions.
+///
+/// In bind mode values become `$n` parameters, starting at the given index.
+/// Inline mode renders them as escaped literals, for statements that cannot take
+/// parameters such as `CREATE MATERIALIZED VIEW`.
+pub struct SqlWriter {
+    inline: bool,
+    first_param: usize,
+    params: Vec<String>,
+}
+
+impl SqlWriter {
+    pub fn bind(first_param: usize) -> Self {
+        Self {
+            inline: false,
+            first_param,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn inline() -> Self {
+        Self {
+            inline: true,
+            first_param: 1,
+            params: Vec::new(),
+        }
+    }
+
+    /// Parameters to pass along the compiled statement, in order.
+    pub fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.params
+            .iter()
+            .map(|param| param as &(dyn ToSql + Sync))
+            .collect()
+    }
+
+    /// Every value is sent as text and cast in SQL, so all parameters share one type.
+    fn value(&mut self, value: &str, cast: &str) -> String {
+        if self.inline {
+            return format!(
+                "E'{}'::{}",
+                value.replace('\\', "\\\\").replace('\'', "\\'"),
+                cast
+            );
+        }
+        self.params.push(value.to_owned());
+        let param = format!("${}::text", self.first_param + self.params.len() - 1);
+        match cast {
+            "text" => param,
+            _ => format!("{}::{}", param, cast),
+        }
+    }
+
+    fn path(&mut self, path: &[String]) -> String {
+        let items: Vec<String> = path
+            .iter()
+            .map(|segment| self.value(segment, "text"))
+            .collect();
+        format!("ARRAY[{}]", items.join(", "))
+    }
+}
+
+impl Expr {
+    /// Compile the filter to a boolean SQL expression over the `logs` table.
+    pub fn to_sql(&self, writer: &mut SqlWriter) -> String {
+        match self {
+            Expr::All => "true".to_owned(),
+            Expr::And(terms) => join_sql(terms, " AND ", writer),
+            Expr::Or(terms) => join_sql(terms, " OR ", writer),
+            Expr::Not(expr) => format!("NOT {}", expr.to_sql(writer)),
+            Expr::Search(search) => search.to_sql(writer),
+            Expr::Compare { field, op, value } => compare_to_sql(field, *op, value, writer),
+        }
+    }
+}
+
+fn join_sql(terms: &[Expr], operator: &str, writer: &mut SqlWriter) -> String {
+    let terms: Vec<String> = terms.iter().map(|term| term.to_sql(writer)).collect();
+    format!("({})", terms.join(operator))
+}
+
+fn compare_to_sql(field: &Field, op: CompareOp, value: &Value, writer: &mut SqlWriter) -> String {
+    let (raw, quoted) = match value {
+        Value::Any => {
+            return format!("({} IS NOT NULL)", field.to_sql(writer));
+        }
+        Value::Text { raw, quoted } => (raw.as_str(), *quoted),
+    };
+    match (field, op) {
+        (Field::Words, _) => unreachable!("words comparisons are parsed as searches"),
+        (Field::Level | Field::Source, CompareOp::Ne) => format!(
+            "({} IS DISTINCT FROM {})",
+            field.to_sql(writer),
+            writer.value(raw, "text")
+        ),
+        (Field::Level | Field::Source, op) => format!(
+            "({} {} {})",
+            field.to_sql(writer),
+            op,
+            writer.value(raw, "text")
+        ),
+        (Field::Data(path), CompareOp::Eq) => data_eq_to_sql(path, raw, quoted, writer),
+        (Field::Data(path), CompareOp::Ne) => {
+            format!("NOT {}", data_eq_to_sql(path, raw, quoted, writer))
+        }
+        (Field::Data(path), op) => {
+            let path_sql = writer.path(path);
+            if raw.parse::<f64>().is_ok() && !quoted {
+                format!(
+                    "(CASE WHEN jsonb_typeof(logdata #> {path}) = 'number' THEN (logdata #>> {path})::float8 {} {} ELSE false END)",
+                    op,
+                    writer.value(raw, "float8"),
+                    path = path_sql,
+                )
+            } else {
+                format!(
+                    "COALESCE((logdata #>> {}) {} {}, false)",
+                    path_sql,
+                    op,
+                    writer.value(raw, "text")
+                )
+            }
+        }
+    }
+}
+
+/// Equality on `logdata` is written as containment so that it can use the GIN index.
+/// Unquoted values that read as JSON scalars also match their typed form, `status:500`
+/// matching both `{"status": 500}` and `{"status": "500"}`.
+fn data_eq_to_sql(path: &[String], raw: &str, quoted: bool, writer: &mut SqlWriter) -> String {
+    let mut candidates = vec![serde_json::Value::String(raw.to_owned())];
+    if !quoted {
+        if let Ok(typed) = serde_json::from_str::<serde_json::Value>(raw) {
+            if typed.is_number() || typed.is_boolean() || typed.is_null() {
+                candidates.push(typed);
+            }
+        }
+    }
+    let conditions: Vec<String> = candidates
+        .into_iter()
+        .map(|candidate| {
+            let document = path.iter().rev().fold(candidate, |inner, key| {
+                let mut object = serde_json::Map::new();
+                object.insert(key.clone(), inner);
+                object.into()
+            });
+            format!(
+                "logdata @> {}",
+                writer.value(&document.to_string(), "jsonb")
+            )
+        })
+        .collect();
+    format!("({})", conditions.join(" OR "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compare(path: &[&str], op: CompareOp, raw: &str, quoted: bool) -> Expr {
+        Expr::Compare {
+            field: Field::Data(path.iter().map(|segment| segment.to_string()).collect()),
+            op,
+            value: Value::Text {
+                raw: raw.to_owned(),
+                quoted,
+            },
+        }
+    }
+
+    fn inline(query: &str) -> String {
+        parse(query).unwrap().to_sql(&mut SqlWriter::inline())
+    }
+
+    #[test]
+    fn empty_and_star_match_everything() {
+        assert_eq!(parse(""), Ok(Expr::All));
+        assert_eq!(parse("   "), Ok(Expr::All));
+        assert_eq!(parse("*"), Ok(Expr::All));
+        assert_eq!(inline("*"), "true");
+    }
+
+    #[test]
+    fn parses_comparisons() {
+        assert_eq!(
+            parse("level:ERROR"),
+            Ok(Expr::Compare {
+                field: Field::Level,
+                op: CompareOp::Eq,
+                value: Value::Text {
+                    raw: "ERROR".to_owned(),
+                    quoted: false
+                },
+            })
+        );
+        assert_eq!(
+            parse("logdata.http.status>=500"),
+            Ok(compare(&["http", "status"], CompareOp::Ge, "500", false))
+        );
+        assert_eq!(
+            parse("host!=\"some-host\""),
+            Ok(compare(&["host"], CompareOp::Ne, "some-host", true))
+        );
+        assert_eq!(
+            parse("user:*"),
+            Ok(Expr::Compare {
+                field: Field::Data(vec!["user".to_owned()]),
+                op: CompareOp::Eq,
+                value: Value::Any,
+            })
+        );
+    }
+
+    #[test]
+    fn juxtaposed_terms_are_one_flat_and() {
+        let expected = Expr::And(vec![
+            compare(&["a"], CompareOp::Eq, "1", false),
+            compare(&["b"], CompareOp::Eq, "2", false),
+            compare(&["c"], CompareOp::Eq, "3", false),
+        ]);
+        assert_eq!(parse("a:1 b:2 c:3"), Ok(expected.clone()));
+        assert_eq!(parse("a:1 AND b:2 AND c:3"), Ok(expected));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("a:1 OR b:2 c:3 OR NOT d:4"),
+            Ok(Expr::Or(vec![
+                compare(&["a"], CompareOp::Eq, "1", false),
+                Expr::And(vec![
+                    compare(&["b"], CompareOp::Eq, "2", false),
+                    compare(&["c"], CompareOp::Eq, "3", false),
+                ]),
+                Expr::Not(Box::new(compare(&["d"], CompareOp::Eq, "4", false))),
+            ]))
+        );
+        assert_eq!(
+            inline("(a:1 OR b:2) c:3"),
+            format!(
+                "(({} OR {}) AND {})",
+                inline("a:1"),
+                inline("b:2"),
+                inline("c:3")
+            )
+        );
+    }
+
+    #[test]
+    fn searches_words_prefixes_and_phrases() {
+        assert_eq!(inline("timeout"), "(words @> ARRAY[E'timeout'::text])");
+        assert_eq!(
+            inline("conn*"),
+            "(EXISTS (SELECT 1 FROM unnest(words) AS word WHERE word LIKE E'conn%'::text))"
+        );
+        assert_eq!(
+            inline("\"connection reset\""),
+            "(words @> ARRAY[E'connection'::text, E'reset'::text] AND strpos(logdata::text, E'connection reset'::text) > 0)"
+        );
+        assert_eq!(
+            inline("words!=debug"),
+            "NOT (words @> ARRAY[E'debug'::text])"
+        );
+    }
+
+    #[test]
+    fn inline_values_are_escaped() {
+        assert_eq!(
+            inline(r#"level:"it's \"quoted\" \\ here""#),
+            r#"(level = E'it\'s "quoted" \\ here'::text)"#
+        );
+        assert_eq!(
+            inline(r#"source:"x'); DROP TABLE logs; --""#),
+            r#"(source = E'x\'); DROP TABLE logs; --'::text)"#
+        );
+        // the underscore of a prefix is a literal, not a LIKE wildcard
+        assert_eq!(
+            inline("a_b*"),
+            r"(EXISTS (SELECT 1 FROM unnest(words) AS word WHERE word LIKE E'a\\_b%'::text))"
+        );
+    }
+
+    #[test]
+    fn data_equality_uses_containment() {
+        assert_eq!(
+            inline("http.status:500"),
+            r#"(logdata @> E'{"http":{"status":"500"}}'::jsonb OR logdata @> E'{"http":{"status":500}}'::jsonb)"#
+        );
+        assert_eq!(
+            inline("status:\"500\""),
+            r#"(logdata @> E'{"status":"500"}'::jsonb)"#
+        );
+        assert_eq!(
+            inline("cpu>5"),
+            "(CASE WHEN jsonb_typeof(logdata #> ARRAY[E'cpu'::text]) = 'number' THEN (logdata #>> ARRAY[E'cpu'::text])::float8 > E'5'::float8 ELSE false END)"
+        );
+    }
+
+    #[test]
+    fn bound_values_become_parameters() {
+        let mut writer = SqlWriter::bind(4);
+        let sql = parse("level:ERROR \"it's\"").unwrap().to_sql(&mut writer);
+        assert_eq!(
+            sql,
+            "((level = $4::text) AND (words @> ARRAY[$5::text, $6::text] AND strpos(logdata::text, $7::text) > 0))"
+        );
+        assert_eq!(writer.params().len(), 4);
+    }
+
+    #[test]
+    fn parses_columns() {
+        assert_eq!(parse_column("level"), Ok(Field::Level));
+        assert_eq!(
+            parse_column("logdata.a.b"),
+            Ok(Field::Data(vec!["a".to_owned(), "b".to_owned()]))
+        );
+        assert_eq!(
+            parse_column("\"with space\""),
+            Ok(Field::Data(vec!["with space".to_owned()]))
+        );
+        assert!(parse_column("").is_err());
+        assert!(parse_column("a b").is_err());
+        assert!(parse_column("(").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        for (query, position, message) in [
+            ("\"open", 0, "unterminated quoted string"),
+            ("a ! b", 2, "expected '=' after '!'"),
+            ("(a:1", 4, "expected ')', found end of query"),
+            ("a:1)", 3, "unexpected ')'"),
+            (
+                "level:",
+                6,
+                "expected a value after '=', found end of query",
+            ),
+            ("level:(", 6, "expected a value after '=', found '('"),
+            ("a OR", 4, "expected a term, found end of query"),
+            ("AND a", 0, "expected a term, found AND"),
+            ("a>*", 1, "'*' can only follow ':', not '>'"),
+            ("words>a", 5, "'words' cannot be compared with '>'"),
+            ("logdata:1", 0, "'logdata' needs a field path"),
+            ("\"--\"", 0, "\"--\" has no word to search"),
+        ] {
+            assert_eq!(
+                parse(query),
+                Err(ParseError {
+                    position,
+                    message: message.to_owned()
+                }),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn caps_nesting_depth() {
+        let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            parse(&nested(MAX_DEPTH + 1)).unwrap_err().position,
+            MAX_DEPTH
+        );
+        assert!(parse(&format!("{}a", "NOT ".repeat(MAX_DEPTH))).is_ok());
+        assert!(parse(&format!("{}a", "NOT ".repeat(MAX_DEPTH + 1))).is_err());
+        assert!(parse(&"(NOT ".repeat(MAX_DEPTH)).is_err());
+    }
+
+    #[test]
+    fn caps_terms_and_paths() {
+        assert!(parse(&"a ".repeat(MAX_TERMS)).is_ok());
+        assert_eq!(
+            parse(&"a ".repeat(MAX_TERMS + 1)).unwrap_err().message,
+            format!("a query has at most {} terms", MAX_TERMS)
+        );
+        let path = |segments: usize| vec!["a"; segments].join(".");
+        assert!(parse(&format!("{}:1", path(MAX_PATH_SEGMENTS))).is_ok());
+        assert!(parse(&format!("{}:1", path(MAX_PATH_SEGMENTS + 1))).is_err());
+        assert!(parse_column(&path(MAX_PATH_SEGMENTS + 1)).is_err());
+    }
+
+    #[test]
+    fn rejects_hostile_queries_without_overflowing() {
+        for query in [
+            "(".repeat(2000),
+            "a ".repeat(10000),
+            "NOT ".repeat(10000),
+            "a OR ".repeat(10000),
+            format!("{}:1", vec!["a"; 10000].join(".")),
+        ] {
+            assert!(parse(&query).is_err(), "{}", &query[..20]);
+        }
+    }
+
+    #[test]
+    fn rejects_numbers_out_of_range() {
+        for query in ["cpu>1e999", "cpu<=-inf", "cpu>NaN", "cpu<infinity"] {
+            let error = parse(query).unwrap_err();
+            assert!(error.message.contains("not a finite number"), "{}", query);
+        }
+        assert!(parse("cpu>1e300").is_ok());
+        assert!(parse("cpu:inf").is_ok());
+        assert!(parse("cpu>\"inf\"").is_ok());
+    }
+
+    #[test]
+    fn rejects_legacy_sql_filters() {
+        for filter in [
+            "true",
+            "TRUE",
+            "level = 'ERROR'",
+            "logdata->>'host' = 'a'",
+            "logdata->'request' IS NOT NULL",
+            "level:ERROR and source:api",
+            "(logdata->>'cpu')::float > 5",
+        ] {
+            let error = parse_filter(filter).unwrap_err();
+            assert!(
+                error.message.contains("looks like SQL"),
+                "{}: {}",
+                filter,
+                error
+            );
+        }
+        assert_eq!(parse_filter("*"), Ok(Expr::All));
+        for filter in [
+            "enabled:true",
+            "ok:false",
+            "x:null",
+            "message:don't",
+            "word:and",
+            "don't",
+            "NOT enabled=true",
+        ] {
+            assert_eq!(parse_filter(filter), parse(filter), "{}", filter);
+            assert!(parse_filter(filter).is_ok(), "{}", filter);
+        }
+        assert_eq!(
+            parse_filter("level:ERROR AND \"true\""),
+            parse("level:ERROR AND \"true\"")
+        );
+        assert!(parse_filter("cpu>-5 AND rate>=-1").is_ok());
+    }
+}
@@ -1,9 +1,19 @@
 use std::sync::Arc;
 
-use axum::{routing::get, routing::post, Router};
+use axum::{
+    routing::{get, patch, post, put},
+    Router,
+};
 
 use crate::{
+    export::export_handler,
     handler::{density_handler, health_checker_handler, list_views, logs_handler, view_handler},
+    tail::tail_handler,
+    views::{
+        create_view_handler, delete_column_handler, delete_view_handler, get_column_handler,
+        get_view_handler, list_columns_handler, put_column_handler, rename_view_handler,
+        reorder_columns_handler, update_view_handler,
+    },
     AppState,
 };
 
@@ -12,7 +22,25 @@ pub fn create_router(app_state: Arc<AppState>) -> Router {
         .route("/api/healthchecker", get(health_checker_handler))
         .route("/api/density", post(density_handler))
         .route("/api/logs", post(logs_handler))
+        .route("/api/tail", get(tail_handler))
+        .route("/api/export", post(export_handler))
         .route("/api/listviews", get(list_views))
         .route("/api/createview", post(view_handler))
+        .route("/api/views", get(list_views).post(create_view_handler))
+        .route(
+            "/api/views/:name",
+            get(get_view_handler)
+                .put(update_view_handler)
+                .delete(delete_view_handler),
+        )
+        .route("/api/views/:name/name", patch(rename_view_handler))
+        .route("/api/views/:name/columns", put(reorder_columns_handler))
+        .route("/api/columns", get(list_columns_handler))
+        .route(
+            "/api/columns/:name",
+            get(get_column_handler)
+                .put(put_column_handler)
+                .delete(delete_column_handler),
+        )
         .with_state(app_state)
 }
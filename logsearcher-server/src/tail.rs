@@ -0,0 +1,271 @@
+//! Live tail of a view, streamed as server-sent events.
+//!
+//! New rows are polled from the database and sent as `logs` events holding rows laid out
+//! like `/api/logs` ones. When a view gets more rows than the rate cap, or the client does
+//! not read events fast enough, rows are skipped and a `skipped` event tells how many.
+//!
+//! Row ids are taken from their sequence when rows are inserted, but rows only show once
+//! their transaction commits, and the consumer writers commit concurrently: a row may show
+//! after rows with greater ids. Ids are therefore only considered past once every
+//! transaction which could still hold a lower one has ended, which the snapshots of
+//! successive polls tell, and the ids sent above that are remembered until then.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
+};
+use chrono::Utc;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+use tokio_postgres::{types::ToSql, IsolationLevel};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    handler::{compile_view, db_error, pool_error, row_to_json},
+    model::TailQuery,
+    query::SqlWriter,
+    AppState,
+};
+
+/// How often new rows are looked for.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Rows sent to a client per poll at most, older ones are skipped.
+const MAX_ROWS_PER_POLL: i64 = 200;
+/// Events waiting for a slow client before new ones are skipped.
+const CLIENT_BUFFER: usize = 16;
+/// How long after their event time rows may be ingested and still be tailed.
+const LOOKBACK_SECONDS: i64 = 300;
+/// Longest wait for transactions to end before the ids they may hold are considered past,
+/// so that one long transaction does not make the remembered ids pile up.
+const MAX_COMMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// Last id taken from the `logs` sequence, including by transactions not committed yet.
+const ALLOCATED_SQL: &str = "SELECT last_value FROM logs_id_seq";
+/// Oldest transaction still running, and the first one not started, in the poll snapshot.
+const SNAPSHOT_SQL: &str = "SELECT pg_snapshot_xmin(pg_current_snapshot())::text::bigint, pg_snapshot_xmax(pg_current_snapshot())::text::bigint";
+
+fn skipped_event(reason: &str, rows: i64) -> Result<Event, Infallible> {
+    Ok(Event::default()
+        .event("skipped")
+        .data(serde_json::json!({ "reason": reason, "rows": rows }).to_string()))
+}
+
+/// Which rows of the view were already sent or skipped.
+struct Seen {
+    /// Every transaction which took an id up to this one has ended.
+    stable: i64,
+    /// Rows below the last rate capped poll were skipped, and so are those committing later.
+    floor: i64,
+    /// Ids handled above `stable` and `floor`.
+    ids: BTreeSet<i64>,
+    /// Last id taken from the sequence at the previous poll.
+    allocated: i64,
+    /// Ids taken from the sequence, with the snapshot `xmax` of the poll after they were read,
+    /// which become stable once every transaction before that `xmax` ended.
+    pending: VecDeque<(i64, i64, Instant)>,
+}
+
+impl Seen {
+    fn new(allocated: i64) -> Self {
+        Self {
+            stable: allocated,
+            floor: allocated,
+            ids: BTreeSet::new(),
+            allocated,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Record a poll: `allocated` was read from the sequence before its snapshot was taken.
+    ///
+    /// An id read at the previous poll was taken by a transaction which started, and got its
+    /// transaction id, before this snapshot, so it is below `xmax`.
+    fn advance(&mut self, allocated: i64, xmin: i64, xmax: i64) {
+        self.pending
+            .push_back((self.allocated, xmax, Instant::now()));
+        self.allocated = allocated;
+        while let Some(&(id, xmax, pushed)) = self.pending.front() {
+            if xmax > xmin && pushed.elapsed() < MAX_COMMIT_WAIT {
+                break;
+            }
+            self.stable = self.stable.max(id);
+            self.pending.pop_front();
+        }
+        self.ids = self.ids.split_off(&(self.lowest() + 1));
+    }
+
+    /// Rows to look for are above this id.
+    fn lowest(&self) -> i64 {
+        self.stable.max(self.floor)
+    }
+}
+
+pub async fn tail_handler(
+    State(data): State<Arc<AppState>>,
+    Query(tail_query): Query<TailQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let client = data.db.get().await.map_err(pool_error)?;
+    // $1 is the lookback start, $2 the id rows are above, $3 a row limit or id bound
+    // and $4 the ids already handled
+    let mut writer = SqlWriter::bind(5);
+    let view = compile_view(&client, &tail_query.table, &tail_query.search, &mut writer).await?;
+    let allocated: i64 = client
+        .query_one(ALLOCATED_SQL, &[])
+        .await
+        .map_err(db_error)?
+        .get(0);
+    drop(client);
+
+    let select_sql = format!(
+        "SELECT time, level, source, {}, id FROM logs WHERE {} AND time >= $1 AND id > $2 AND id <> ALL($4::bigint[]) ORDER BY id DESC LIMIT $3",
+        view.columns, view.condition
+    );
+    let count_sql = format!(
+        "SELECT COUNT(*) FROM logs WHERE {} AND time >= $1 AND id > $2 AND id < $3 AND id <> ALL($4::bigint[])",
+        view.condition
+    );
+    let id_index = view.col_number + 3;
+    let (sender, receiver) = mpsc::channel(CLIENT_BUFFER);
+    tokio::spawn(async move {
+        let fetch_limit = MAX_ROWS_PER_POLL + 1;
+        let mut seen = Seen::new(allocated);
+        let mut lagged: i64 = 0;
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        while !sender.is_closed() {
+            interval.tick().await;
+            let mut client = match data.db.get().await {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            let since = (Utc::now() - chrono::Duration::seconds(LOOKBACK_SECONDS)).naive_utc();
+            // the sequence is read before the snapshot, see `Seen::advance`
+            let polled = async {
+                let allocated: i64 = client.query_one(ALLOCATED_SQL, &[]).await?.get(0);
+                let transaction = client
+                    .build_transaction()
+                    .isolation_level(IsolationLevel::RepeatableRead)
+                    .read_only(true)
+                    .start()
+                    .await?;
+                let snapshot = transaction.query_one(SNAPSHOT_SQL, &[]).await?;
+                seen.advance(allocated, snapshot.get(0), snapshot.get(1));
+                let lowest = seen.lowest();
+                let handled: Vec<i64> = seen.ids.iter().copied().collect();
+                let mut params: Vec<&(dyn ToSql + Sync)> =
+                    vec![&since, &lowest, &fetch_limit, &handled];
+                params.extend(writer.params());
+                let rows = transaction.query(&select_sql, &params).await?;
+                // rows come newest first, the ones past the cap are counted rather than sent
+                let sent = rows.len().min(MAX_ROWS_PER_POLL as usize);
+                let mut rate_skipped: i64 = 0;
+                if rows.len() > sent {
+                    let oldest_sent: i64 = rows[sent - 1].get(id_index);
+                    params[2] = &oldest_sent;
+                    rate_skipped = transaction.query_one(&count_sql, &params).await?.get(0);
+                    seen.floor = oldest_sent - 1;
+                }
+                transaction.commit().await?;
+                seen.ids
+                    .extend(rows[..sent].iter().map(|r| r.get::<_, i64>(id_index)));
+                Ok::<_, tokio_postgres::Error>((rows, sent, rate_skipped))
+            };
+            let (rows, sent, rate_skipped) = match polled.await {
+                Ok(polled) => polled,
+                Err(error) => {
+                    let _ = sender
+                        .send(Ok(Event::default().event("error").data(error.to_string())))
+                        .await;
+                    break;
+                }
+            };
+            if rows.is_empty() {
+                continue;
+            }
+            let batch: Vec<Vec<serde_json::Value>> = rows[..sent]
+                .iter()
+                .rev()
+                .map(|r| row_to_json(r, view.col_number))
+                .collect();
+
+            if lagged > 0
+                && sender
+                    .try_send(skipped_event("slow_client", lagged))
+                    .is_ok()
+            {
+                lagged = 0;
+            }
+            if rate_skipped > 0
+                && sender
+                    .try_send(skipped_event("rate_limit", rate_skipped))
+                    .is_err()
+            {
+                lagged += rate_skipped;
+            }
+            let event = Event::default().event("logs").json_data(&batch).unwrap();
+            match sender.try_send(Ok(event)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => lagged += batch.len() as i64,
+                Err(TrySendError::Closed(_)) => break,
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(receiver)).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_become_stable_once_earlier_transactions_ended() {
+        let mut seen = Seen::new(100);
+        // ids up to 150 taken, transactions 10 to 19 running
+        seen.advance(150, 10, 20);
+        assert_eq!(seen.lowest(), 100);
+        seen.ids.extend([120, 140]);
+        // id 150 was read before this snapshot, where transaction 25 is the next to start
+        seen.advance(180, 12, 25);
+        assert_eq!(seen.lowest(), 100);
+        assert_eq!(seen.ids, BTreeSet::from([120, 140]));
+        // transactions below 25 all ended, so every id up to 150 shows or never will
+        seen.advance(200, 25, 30);
+        assert_eq!(seen.lowest(), 150);
+        assert!(seen.ids.is_empty());
+    }
+
+    #[test]
+    fn late_rows_stay_wanted_until_stable() {
+        let mut seen = Seen::new(0);
+        seen.advance(10, 5, 8);
+        seen.ids.extend([9, 10]);
+        // id 3 of a transaction still running has not shown yet, and is still looked for
+        seen.advance(12, 5, 9);
+        assert_eq!(seen.lowest(), 0);
+        assert!(!seen.ids.contains(&3));
+    }
+
+    #[test]
+    fn rate_capped_rows_are_not_looked_for_again() {
+        let mut seen = Seen::new(0);
+        seen.advance(500, 1, 2);
+        seen.floor = 299;
+        seen.ids.extend(300..=500);
+        seen.advance(600, 1, 3);
+        assert_eq!(seen.lowest(), 299);
+        assert_eq!(seen.ids.first(), Some(&300));
+    }
+}
@@ -0,0 +1,329 @@
+//! REST endpoints over saved views (`filters` with their `column_filter` rows) and columns (`cols`).
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+
+use crate::{
+    handler::{check_view_name, db_error, pool_error, save_view, DELETE_ORPHAN_COLUMNS},
+    model::{ColumnDef, ColumnQuery, RenameQuery, ViewDef, ViewQuery},
+    query, AppState,
+};
+
+/// The view of all logs, created with the database, which cannot be renamed or deleted.
+const DEFAULT_VIEW: &str = "logs";
+
+fn not_found(kind: &str, name: &str) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("{} {} not found", kind, name),
+    )
+}
+
+async fn view_exists(
+    client: &deadpool_postgres::Client,
+    name: &String,
+) -> Result<bool, (StatusCode, String)> {
+    let row = client
+        .query_opt("SELECT 1 FROM filters WHERE name = $1", &[name])
+        .await
+        .map_err(db_error)?;
+    Ok(row.is_some())
+}
+
+fn check_not_default(name: &str) -> Result<(), (StatusCode, String)> {
+    if name == DEFAULT_VIEW {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("the {} view cannot be renamed or deleted", DEFAULT_VIEW),
+        ));
+    }
+    Ok(())
+}
+
+pub async fn get_view_handler(
+    State(data): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let client = data.db.get().await.map_err(pool_error)?;
+    let filter = client
+        .query_opt("SELECT query FROM filters WHERE name = $1", &[&name])
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| not_found("view", &name))?;
+    let columns = client
+        .query(
+            "SELECT cols.name, cols.query FROM column_filter JOIN cols ON cols.name = column_filter.column_name WHERE column_filter.filter_name = $1 ORDER BY idx",
+            &[&name],
+        )
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|r| ColumnDef {
+            name: r.get(0),
+            query: r.get(1),
+        })
+        .collect();
+    Ok(Json(ViewDef {
+        name,
+        query: filter.get(0),
+        columns,
+    }))
+}
+
+pub async fn create_view_handler(
+    State(data): State<Arc<AppState>>,
+    Json(mut view_query): Json<ViewQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if view_query.filter.name.is_empty() {
+        view_query.filter.name = DEFAULT_VIEW.to_owned();
+    }
+    let client = data.db.get().await.map_err(pool_error)?;
+    if view_exists(&client, &view_query.filter.name).await? {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("view {} already exists", view_query.filter.name),
+        ));
+    }
+    drop(client);
+    save_view(&data, view_query).await
+}
+
+pub async fn update_view_handler(
+    State(data): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(mut view_query): Json<ViewQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let client = data.db.get().await.map_err(pool_error)?;
+    if !view_exists(&client, &name).await? {
+        return Err(not_found("view", &name));
+    }
+    drop(client);
+    view_query.filter.name = name;
+    save_view(&data, view_query)
+        .await
+        .map(|(_, body)| (StatusCode::OK, body))
+}
+
+pub async fn rename_view_handler(
+    State(data): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(rename_query): Json<RenameQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_not_default(&name)?;
+    check_view_name(&name)?;
+    let new_name = rename_query.name;
+    check_view_name(&new_name)?;
+    let mut client = data.db.get().await.map_err(pool_error)?;
+    if !view_exists(&client, &name).await? {
+        return Err(not_found("view", &name));
+    }
+    if view_exists(&client, &new_name).await? {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("view {} already exists", new_name),
+        ));
+    }
+    let transaction = client.transaction().await.map_err(db_error)?;
+    transaction
+        .execute(
+            "UPDATE filters SET name = $2 WHERE name = $1",
+            &[&name, &new_name],
+        )
+        .await
+        .map_err(db_error)?;
+    transaction
+        .execute(
+            "UPDATE column_filter SET filter_name = $2 WHERE filter_name = $1",
+            &[&name, &new_name],
+        )
+        .await
+        .map_err(db_error)?;
+    transaction
+        .batch_execute(&format!(
+            "ALTER MATERIALIZED VIEW IF EXISTS {}_sec_count RENAME TO {}_sec_count; ALTER MATERIALIZED VIEW IF EXISTS {}_min_count RENAME TO {}_min_count;",
+            name, new_name, name, new_name
+        ))
+        .await
+        .map_err(db_error)?;
+    transaction.commit().await.map_err(db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_view_handler(
+    State(data): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_not_default(&name)?;
+    check_view_name(&name)?;
+    let mut client = data.db.get().await.map_err(pool_error)?;
+    if !view_exists(&client, &name).await? {
+        return Err(not_found("view", &name));
+    }
+    let transaction = client.transaction().await.map_err(db_error)?;
+    transaction
+        .execute("DELETE FROM filters WHERE name = $1", &[&name])
+        .await
+        .map_err(db_error)?;
+    transaction
+        .execute("DELETE FROM column_filter WHERE filter_name = $1", &[&name])
+        .await
+        .map_err(db_error)?;
+    transaction
+        .execute(DELETE_ORPHAN_COLUMNS, &[])
+        .await
+        .map_err(db_error)?;
+    // dropping the continuous aggregates also removes their refresh policies
+    transaction
+        .batch_execute(&format!(
+            "DROP MATERIALIZED VIEW IF EXISTS {}_sec_count; DROP MATERIALIZED VIEW IF EXISTS {}_min_count;",
+            name, name
+        ))
+        .await
+        .map_err(db_error)?;
+    transaction.commit().await.map_err(db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reorder the columns of a view, given all its column names in their new order.
+pub async fn reorder_columns_handler(
+    State(data): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(column_names): Json<Vec<String>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut client = data.db.get().await.map_err(pool_error)?;
+    if !view_exists(&client, &name).await? {
+        return Err(not_found("view", &name));
+    }
+    let transaction = client.transaction().await.map_err(db_error)?;
+    let mut current: Vec<String> = transaction
+        .query(
+            "SELECT column_name FROM column_filter WHERE filter_name = $1 FOR UPDATE",
+            &[&name],
+        )
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|r| r.get(0))
+        .collect();
+    let mut requested = column_names.clone();
+    current.sort();
+    requested.sort();
+    if current != requested {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "expected every column of view {} exactly once: {}",
+                name,
+                current.join(", ")
+            ),
+        ));
+    }
+    transaction
+        .execute(
+            "UPDATE column_filter SET idx = (t.idx - 1)::int FROM UNNEST($2::text[]) WITH ORDINALITY AS t(name, idx) WHERE column_filter.filter_name = $1 AND column_filter.column_name = t.name",
+            &[&name, &column_names],
+        )
+        .await
+        .map_err(db_error)?;
+    transaction.commit().await.map_err(db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_columns_handler(
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let client = data.db.get().await.map_err(pool_error)?;
+    let columns: Vec<ColumnDef> = client
+        .query("SELECT name, query FROM cols ORDER BY name", &[])
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|r| ColumnDef {
+            name: r.get(0),
+            query: r.get(1),
+        })
+        .collect();
+    Ok(Json(columns))
+}
+
+pub async fn get_column_handler(
+    State(data): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let client = data.db.get().await.map_err(pool_error)?;
+    let row = client
+        .query_opt("SELECT name, query FROM cols WHERE name = $1", &[&name])
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| not_found("column", &name))?;
+    Ok(Json(ColumnDef {
+        name: row.get(0),
+        query: row.get(1),
+    }))
+}
+
+/// Create or change a column, which applies to every view using it.
+pub async fn put_column_handler(
+    State(data): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(column_query): Json<ColumnQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    query::parse_column(&column_query.query).map_err(|error| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("column {} {}", name, error),
+        )
+    })?;
+    let client = data.db.get().await.map_err(pool_error)?;
+    let created = client
+        .query_one(
+            "INSERT INTO cols (name, query) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET query = EXCLUDED.query RETURNING xmax = 0",
+            &[&name, &column_query.query],
+        )
+        .await
+        .map_err(db_error)?
+        .get::<_, bool>(0);
+    Ok(match created {
+        true => StatusCode::CREATED,
+        false => StatusCode::OK,
+    })
+}
+
+pub async fn delete_column_handler(
+    State(data): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut client = data.db.get().await.map_err(pool_error)?;
+    let transaction = client.transaction().await.map_err(db_error)?;
+    let views: Vec<String> = transaction
+        .query(
+            "SELECT filter_name FROM column_filter WHERE column_name = $1 ORDER BY filter_name",
+            &[&name],
+        )
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|r| r.get(0))
+        .collect();
+    if !views.is_empty() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("column {} is used by views {}", name, views.join(", ")),
+        ));
+    }
+    let deleted = transaction
+        .execute("DELETE FROM cols WHERE name = $1", &[&name])
+        .await
+        .map_err(db_error)?;
+    if deleted == 0 {
+        return Err(not_found("column", &name));
+    }
+    transaction.commit().await.map_err(db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}